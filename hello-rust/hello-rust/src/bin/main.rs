@@ -7,6 +7,7 @@ use esp_hal::{
     uart::{Uart, Config as UartConfig},
     DriverMode,
     gpio::{Output, Level, OutputConfig},
+    rtc_cntl::{Rtc, RwdtStage},
 };
 use esp_println::println;
 use esp_hal::time::{Instant, Duration};
@@ -14,13 +15,482 @@ use esp_hal::time::{Instant, Duration};
 esp_bootloader_esp_idf::esp_app_desc!();
 
 // --- Konfigurasi ---
+// Dicetak sekali di boot sebagai banner `FW:x.y.z` supaya influx-reader bisa
+// menandai titik data dengan versi firmware yang menghasilkannya -- naikkan
+// ini setiap rilis OTA supaya rollout bisa dikonfirmasi lewat distribusi tag.
+const FW_VERSION: &str = "1.0.0";
+
 const BAUD: u32 = 9_600; // Gunakan 9600 yang sudah terbukti bekerja untuk sensor
-const SID:  u8  = 1;
+
+// Jumlah register maksimum yang didukung sekali baca (quantity di permintaan
+// Modbus). RH+T (2) cukup untuk unit lama; model sensor yang juga mengekspos
+// pressure di register berikutnya tinggal set `SENSOR.register_count = 3`,
+// tidak perlu ubah `poll_slave`. Dipilih tetap (bukan Vec) karena ini no_std.
+const MAX_REGISTERS: usize = 3;
+
+/// Peta register Modbus satu model sensor: slave id, register awal (register
+/// berikutnya dibaca berurutan sebanyak `register_count`), dan divisor skala
+/// raw register -> nilai fisik per register (diindeks sejajar urutan
+/// register di respons; slot di luar `register_count` diabaikan). Sensor
+/// SHT-style kami pakai divisor 10 untuk RH dan T; ganti literal `SENSOR` ini
+/// saja untuk pindah model, tidak perlu sentuh kode di `main`.
+struct SensorMap {
+    slave_id: u8,
+    start_register: u16,
+    register_count: u16,
+    divisors: [f32; MAX_REGISTERS],
+    // 0x04 (input register) pada sensor lama, 0x03 (holding register) pada
+    // model pengganti yang mengekspos nilainya lewat holding register.
+    // Parsing respons identik untuk keduanya, hanya function code-nya beda.
+    read_function: u8,
+}
+
+const SENSOR: SensorMap = SensorMap {
+    slave_id: 1,
+    start_register: 0x0001, // RH lalu T, berurutan seperti sebelumnya
+    register_count: 2,
+    divisors: [10.0, 10.0, 1.0],
+    read_function: 0x04,
+};
+
+// Beberapa varian sensor mengekspos scale factor RH/T mereka sendiri lewat
+// holding register alih-alih selalu pakai 10.0 tetap seperti model SHT kami.
+// `None` (default) berarti tidak ada register seperti itu di sensor yang
+// dipasang -- `SENSOR.divisors` hardcoded tetap dipakai seperti sebelumnya.
+const SCALE_REGISTER: Option<u16> = None;
+
+// Tiga sensor di bus RS-485 yang sama, dibaca bergilir tiap siklus. SID
+// pertama di array ini tetap dipakai sebagai acuan kontrol relay supaya
+// perilaku unit yang sudah terpasang (satu sensor, SID=1) tidak berubah.
+const SLAVE_IDS: [u8; 3] = [1, 2, 3];
+
+// Hysteresis relay: naik di atas ON, baru turun di bawah OFF, supaya relay
+// tidak chattering saat suhu berada tepat di sekitar setpoint.
+const RELAY_ON_TEMP:  f32 = 27.0;
+const RELAY_OFF_TEMP: f32 = 26.0;
+
+/// Arah kerja relay: `AboveEngages` menyala saat suhu naik melewati
+/// `engage_temp` (mis. fan/cooling), `BelowEngages` menyala saat suhu turun
+/// melewati `engage_temp` (mis. heater). `release_temp` selalu yang
+/// memadamkannya kembali, di sisi yang berlawanan, supaya hysteresis tetap
+/// berlaku pada kedua arah.
+#[derive(Clone, Copy)]
+enum RelayDirection {
+    AboveEngages,
+    BelowEngages,
+}
+
+struct RelayConfig {
+    engage_temp: f32,
+    release_temp: f32,
+    direction: RelayDirection,
+}
+
+// Default lama: relay1 dan relay2 berdua menyala di atas 27°C, padam di
+// bawah 26°C (keduanya AboveEngages). Untuk pemisahan heater/fan, ganti
+// salah satunya jadi BelowEngages dengan setpoint masing-masing.
+const RELAY1_CONFIG: RelayConfig = RelayConfig {
+    engage_temp: RELAY_ON_TEMP,
+    release_temp: RELAY_OFF_TEMP,
+    direction: RelayDirection::AboveEngages,
+};
+const RELAY2_CONFIG: RelayConfig = RelayConfig {
+    engage_temp: RELAY_ON_TEMP,
+    release_temp: RELAY_OFF_TEMP,
+    direction: RelayDirection::AboveEngages,
+};
+
+// Sebagian modul relay board aktif-low (koil energize saat pin logic low), jadi
+// `set_high`/`set_low` mentah terbalik untuk board seperti itu. Default `false`
+// berarti aktif-high (set_high = ON). Ganti ke `true` kalau
+// board yang dipasang aktif-low -- semua drive relay lewat `set_relay` di
+// bawah supaya satu konstanta ini cukup, tidak perlu menambal tiap pemanggil.
+const RELAY_ACTIVE_LOW: bool = false;
+
+/// Satu titik drive untuk semua relay: `on` selalu berarti "energized"
+/// secara logis, terlepas dari board aktif-high atau aktif-low -- pemanggil
+/// tidak perlu tahu/ingat polaritas hardware, cukup urus state on/off.
+fn set_relay(pin: &mut Output<'_>, on: bool) {
+    let drive_high = on != RELAY_ACTIVE_LOW;
+    if drive_high {
+        pin.set_high();
+    } else {
+        pin.set_low();
+    }
+}
+
+/// Evaluasi hysteresis satu relay terhadap `cfg`-nya sendiri. Dipanggil
+/// terpisah untuk relay1/relay2 supaya masing-masing bisa punya arah dan
+/// setpoint sendiri (heater vs fan) tanpa duplikasi logika.
+fn relay_next_state(currently_on: bool, temp: f32, cfg: &RelayConfig) -> bool {
+    match cfg.direction {
+        RelayDirection::AboveEngages => {
+            if !currently_on && temp > cfg.engage_temp {
+                true
+            } else if currently_on && temp < cfg.release_temp {
+                false
+            } else {
+                currently_on
+            }
+        }
+        RelayDirection::BelowEngages => {
+            if !currently_on && temp < cfg.engage_temp {
+                true
+            } else if currently_on && temp > cfg.release_temp {
+                false
+            } else {
+                currently_on
+            }
+        }
+    }
+}
+
+// Delay setelah `uart.flush()` sebelum mulai membaca respons: transceiver
+// RS-485 half-duplex (mis. MAX485) butuh waktu singkat untuk berpindah dari
+// mode transmit ke receive setelah byte terakhir selesai dikirim di kabel;
+// tanpa ini byte pertama respons slave kadang termakan saat transceiver
+// masih switching. 500us cukup longgar untuk baud 9600 tanpa menambah
+// latensi yang terasa.
+const DE_RE_TURNAROUND: Duration = Duration::from_micros(500);
+
+/// Nyalakan/matikan pin driver-enable RS-485 di sekitar transmit. `None`
+/// berarti transceiver yang dipakai auto-direction (DE/RE diikat ke
+/// TX-enable oleh hardware) jadi tidak perlu GPIO terpisah — dipanggil tetap
+/// aman lewat no-op di cabang `None` itu.
+fn set_driver_enable(de_pin: &mut Option<Output<'_>>, transmitting: bool) {
+    if let Some(pin) = de_pin {
+        if transmitting {
+            pin.set_high();
+        } else {
+            pin.set_low();
+        }
+    }
+}
+
+// Self-test boot: pulsa relay1 lalu relay2 sekali tiap nyala, supaya
+// teknisi yang baru pasang unit bisa dengar/lihat klik kontaktornya tanpa
+// harus menunggu suhu melewati setpoint dulu. Default false karena
+// menambah sekitar 1 detik (2 x SELF_TEST_PULSE) ke waktu boot dan mengaktifkan relay tanpa alasan
+// termal -- nyalakan sementara saat commissioning lewat rebuild firmware.
+const SELF_TEST_ON_BOOT: bool = false;
+const SELF_TEST_PULSE: Duration = Duration::from_millis(500);
+
+/// Nyalakan lalu matikan relay1, lalu relay2, masing-masing `SELF_TEST_PULSE`,
+/// mencetak setiap langkahnya supaya bisa diamati lewat serial monitor saat
+/// commissioning di lapangan.
+fn run_self_test(relay1: &mut Output<'_>, relay2: &mut Output<'_>) {
+    println!("SELFTEST: relay1 ON");
+    set_relay(relay1, true);
+    sleep(SELF_TEST_PULSE);
+    set_relay(relay1, false);
+    println!("SELFTEST: relay1 OFF");
+
+    println!("SELFTEST: relay2 ON");
+    set_relay(relay2, true);
+    sleep(SELF_TEST_PULSE);
+    set_relay(relay2, false);
+    println!("SELFTEST: relay2 OFF");
+
+    println!("SELFTEST: selesai");
+}
+
+// Dwell minimum antar toggle relay: sekali berganti status, relay tidak
+// boleh berganti lagi sebelum durasi ini lewat, walau suhu sudah melewati
+// setpoint hysteresis-nya. Meredam chattering mekanis pada kontaktor yang
+// dipicu oleh suhu yang naik-turun cepat di sekitar ambang, di atas yang
+// sudah diredam SAMPLES_PER_REPORT (itu meredam noise sensor, ini meredam
+// umur kontak relay).
+const RELAY_MIN_DWELL: Duration = Duration::from_secs(30);
+
+// Cetak CRC yang diterima vs dihitung saat frame gagal validasi. Sudah pernah
+// menyelamatkan kami saat mendiagnosis slave yang mengirim CRC big-endian;
+// biarkan false di produksi karena menambah noise di setiap frame rusak.
+const CRC_DEBUG: bool = false;
+
+// Cetak STATS setiap sekian siklus loop, bukan setiap siklus, supaya tidak
+// membanjiri log serial di bus yang sehat; cukup untuk memantau tren
+// kegagalan RS-485 dari jauh tanpa mem-parse tiap baris [NO RESPONSE]/[BAD
+// FRAME] satu-satu.
+const STATS_REPORT_EVERY: u32 = 30;
+
+/// Penghitung keberhasilan/kegagalan polling Modbus sejak laporan terakhir.
+/// Dipisah dari akumulator RH/T karena ini soal kesehatan link (CRC/timeout),
+/// bukan soal nilai fisik yang dibaca.
+#[derive(Default)]
+struct ModbusStats {
+    ok: u32,
+    fail: u32,
+}
+
+impl ModbusStats {
+    const fn new() -> Self {
+        Self { ok: 0, fail: 0 }
+    }
+
+    fn record(&mut self, success: bool) {
+        if success {
+            self.ok += 1;
+        } else {
+            self.fail += 1;
+        }
+    }
+
+    fn report_and_reset(&mut self) {
+        println!("STATS:ok={},fail={}", self.ok, self.fail);
+        self.ok = 0;
+        self.fail = 0;
+    }
+}
+
+// RTC WDT mereset chip kalau satu putaran loop tidak selesai dalam waktu
+// ini. Satu siklus normal (3 slave x (MAX_ATTEMPTS retry + 50ms) + sleep
+// 2000ms) jauh di bawah ini; nilai dipilih longgar agar bus yang sedang
+// retry tidak memicu reset palsu.
+const WDT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Jumlah pembacaan valid yang dirata-rata sebelum dicetak/dievaluasi relay.
+// Pada kadensi 2 detik, satu sampel RH/T yang meleset (noise sensor, bukan
+// kegagalan CRC) bisa memicu relay toggle sesaat; merata-ratakan beberapa
+// sampel berurutan meredam spike tunggal itu tanpa menambah delay kontrol
+// secara drastis.
+const SAMPLES_PER_REPORT: usize = 5;
+
+/// Akumulator rata-rata berjalan per slave, satu slot per register yang
+/// dibaca (lihat `MAX_REGISTERS`). Sampel yang gagal CRC/timeout tidak
+/// pernah masuk sini (lihat `poll_slave`, dipanggil hanya saat pembacaan
+/// berhasil), jadi rata-rata selalu dari N pembacaan valid berurutan, bukan
+/// N percobaan.
+#[derive(Clone, Copy)]
+struct SampleAccumulator {
+    sums: [f32; MAX_REGISTERS],
+    count: usize,
+}
+
+impl SampleAccumulator {
+    const fn new() -> Self {
+        Self { sums: [0.0; MAX_REGISTERS], count: 0 }
+    }
+
+    /// Tambahkan satu sampel valid (array register, hanya `register_count`
+    /// elemen pertama yang dipakai); begitu genap `SAMPLES_PER_REPORT`,
+    /// kembalikan rata-ratanya dan reset akumulator untuk batch berikutnya.
+    fn push(&mut self, values: &[f32; MAX_REGISTERS]) -> Option<[f32; MAX_REGISTERS]> {
+        for (sum, v) in self.sums.iter_mut().zip(values.iter()) {
+            *sum += v;
+        }
+        self.count += 1;
+        if self.count >= SAMPLES_PER_REPORT {
+            let mut avg = [0.0f32; MAX_REGISTERS];
+            for (a, sum) in avg.iter_mut().zip(self.sums.iter()) {
+                *a = sum / self.count as f32;
+            }
+            *self = Self::new();
+            Some(avg)
+        } else {
+            None
+        }
+    }
+}
+
+// Suhu chamber kami berubah jauh lebih lambat daripada RH, jadi tidak perlu
+// ditanya tiap siklus -- RH tetap dibaca tiap siklus (responsif), sedangkan
+// register temperature hanya diikutkan tiap kelipatan siklus ini untuk
+// mengurangi jumlah request di bus RS-485. Di antara pembacaan, nilai T
+// terakhir yang diketahui (lihat `last_known_temp` di `main`) dipakai lagi
+// untuk akumulator & cetakan supaya baris output tidak bolong.
+const TEMP_POLL_EVERY_N_CYCLES: u32 = 3;
+
+// Kalau true, baris "SID:..." hanya dicetak saat RH atau T berubah >=
+// PRINT_DEDUP_DELTA dari baris terakhir yang benar-benar dicetak (bukan dari
+// rata-rata batch sebelumnya) -- meredam flood serial console + trafik
+// influx-reader saat kondisi kamar sudah stabil. Logika relay di bawahnya
+// tetap dievaluasi tiap batch terlepas dari keputusan cetak ini, supaya dedup
+// cetak tidak pernah menunda respons relay.
+const PRINT_DEDUP_ENABLED: bool = false;
+const PRINT_DEDUP_DELTA: f32 = 0.2;
+
+// Batas waktu menunggu satu frame Modbus utuh. Di 9600 baud frame terpanjang
+// kita (RH+T, ~9 byte) butuh ~10ms di kabel; margin 50ms di atasnya (lihat
+// `response_timeout`) cukup longgar tanpa membuat slave yang benar-benar
+// tidak merespon menahan giliran lama.
+// Margin di luar waktu transmisi teoretis untuk turnaround RS-485 + jitter
+// OS/scheduler -- timeout yang pas-pasan dengan waktu transmisi akan sering
+// gagal padahal slave masih merespons dalam batas wajar.
+const FRAME_TIMEOUT_MARGIN_MS: u64 = 50;
+
+/// Waktu yang dibutuhkan untuk menerima `frame_bytes` karakter UART (asumsi
+/// 10 bit/karakter: start + 8 data + stop, pola umum 8N1) pada `baud`, plus
+/// `FRAME_TIMEOUT_MARGIN_MS`. Dipakai sebagai timeout `read_response` supaya
+/// menaikkan `BAUD` (9600 -> 19200/115200) otomatis memperketat timeout tanpa
+/// perlu menyetel ulang magic number secara manual.
+fn response_timeout(baud: u32, frame_bytes: usize) -> Duration {
+    let transmit_ms = (frame_bytes as u64 * 10 * 1000) / baud as u64;
+    Duration::from_millis(transmit_ms + FRAME_TIMEOUT_MARGIN_MS)
+}
+
+// ========================= Transport Modbus (RTU/ASCII) =========================
+// Satu perangkat legacy di lokasi ini bicara Modbus ASCII (frame dibungkus
+// `:`...`\r\n`, checksum LRC satu byte) alih-alih RTU biner (CRC16 dua byte)
+// yang dipakai SENSOR/relay kita selama ini. Logika register (fungsi 0x03
+// baca, 0x06 tulis, layout byte response) sama sekali tidak berubah antara
+// keduanya -- hanya framing/checksum transmisi & parsing yang beda, semuanya
+// terkonsentrasi di fungsi-fungsi di bawah (`send_pdu`, `read_response`,
+// `verify_checksum`) supaya poll_slave/read_scale_register/write_register
+// tidak perlu tahu transport mana yang sedang aktif.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ModbusTransport {
+    Rtu,
+    Ascii,
+}
+
+const TRANSPORT: ModbusTransport = ModbusTransport::Rtu;
+
+/// Panjang checksum pada representasi BINER frame (sebelum hex-encode untuk
+/// ASCII): CRC16 RTU 2 byte little-endian, LRC ASCII 1 byte tunggal. Dipakai
+/// menghitung panjang frame yang diharapkan supaya `n >= expected_len` benar
+/// untuk kedua transport.
+const fn checksum_len() -> usize {
+    match TRANSPORT {
+        ModbusTransport::Rtu => 2,
+        ModbusTransport::Ascii => 1,
+    }
+}
+
+/// LRC Modbus ASCII: komplemen dua dari jumlah seluruh byte PDU (bukan
+/// CRC16 seperti RTU) -- dihitung atas PDU biner sebelum di-hex-encode,
+/// persis seperti CRC16 RTU dihitung atas PDU biner sebelum little-endian-nya
+/// ditempel.
+fn lrc(data: &[u8]) -> u8 {
+    let sum: u8 = data.iter().fold(0u8, |acc, &b| acc.wrapping_add(b));
+    (!sum).wrapping_add(1)
+}
+
+/// Tempel checksum sesuai `TRANSPORT` (CRC16 little-endian untuk RTU, LRC
+/// satu byte untuk ASCII) ke `pdu`, ditulis ke `out`. Ini bentuk BINER frame
+/// (sebelum hex-encode ASCII) -- dipakai `send_pdu` untuk transmisi dan
+/// `write_register` untuk membandingkan echo fungsi 0x06 terlepas dari
+/// transport yang aktif. Mengembalikan panjang total (pdu + checksum).
+fn pdu_with_checksum(pdu: &[u8], out: &mut [u8]) -> usize {
+    out[..pdu.len()].copy_from_slice(pdu);
+    match TRANSPORT {
+        ModbusTransport::Rtu => {
+            let crc = crc16(pdu);
+            out[pdu.len()..pdu.len() + 2].copy_from_slice(&crc.to_le_bytes());
+        }
+        ModbusTransport::Ascii => {
+            out[pdu.len()] = lrc(pdu);
+        }
+    }
+    pdu.len() + checksum_len()
+}
+
+/// Encode satu byte jadi 2 karakter hex ASCII uppercase, sesuai spec Modbus ASCII.
+fn hex_byte(b: u8, out: &mut [u8]) {
+    const DIGITS: &[u8; 16] = b"0123456789ABCDEF";
+    out[0] = DIGITS[(b >> 4) as usize];
+    out[1] = DIGITS[(b & 0x0F) as usize];
+}
+
+/// Decode satu karakter hex ASCII (huruf besar/kecil) jadi nibble-nya; `None`
+/// kalau bukan hex valid -- frame ASCII yang corrupt di tengah jalan, lihat
+/// `decode_ascii_hex`.
+fn hex_nibble(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        _ => None,
+    }
+}
+
+/// Bangun frame Modbus ASCII lengkap (`:` + hex(pdu) + hex(lrc) + `\r\n`) dari
+/// PDU biner (tanpa checksum) ke `out`, mengembalikan jumlah byte yang
+/// ditulis. `out` harus cukup besar untuk `1 + (pdu.len()+1)*2 + 2` byte.
+fn build_ascii_frame(pdu: &[u8], out: &mut [u8]) -> usize {
+    let mut pos = 0;
+    out[pos] = b':';
+    pos += 1;
+    for &b in pdu {
+        hex_byte(b, &mut out[pos..pos + 2]);
+        pos += 2;
+    }
+    hex_byte(lrc(pdu), &mut out[pos..pos + 2]);
+    pos += 2;
+    out[pos] = b'\r';
+    out[pos + 1] = b'\n';
+    pos + 2
+}
+
+/// Decode byte-byte hex ASCII (tanpa `:` depan / CRLF belakang, lihat
+/// pemanggil) jadi biner (PDU+checksum) ke `out`, mengembalikan jumlahnya.
+/// `None` kalau panjangnya ganjil atau ada karakter non-hex -- sinyal frame
+/// corrupt, berperan sama seperti CRC mismatch di RTU.
+fn decode_ascii_hex(ascii: &[u8], out: &mut [u8]) -> Option<usize> {
+    if ascii.len() % 2 != 0 {
+        return None;
+    }
+    let n = ascii.len() / 2;
+    for i in 0..n {
+        let hi = hex_nibble(ascii[i * 2])?;
+        let lo = hex_nibble(ascii[i * 2 + 1])?;
+        out[i] = (hi << 4) | lo;
+    }
+    Some(n)
+}
+
+/// Kirim satu PDU Modbus (tanpa checksum) lewat transport aktif (`TRANSPORT`),
+/// termasuk toggle DE/RE di sekitarnya -- titik tunggal ini menggantikan
+/// "tempel CRC16 lalu tulis biner" yang dulu diulang di setiap fungsi
+/// (poll_slave, read_scale_register, write_register); sekarang ketiganya
+/// otomatis ikut transport aktif tanpa menduplikasi percabangan RTU/ASCII.
+fn send_pdu(uart: &mut Uart<'_, impl DriverMode>, de_pin: &mut Option<Output<'_>>, pdu: &[u8]) {
+    set_driver_enable(de_pin, true);
+    match TRANSPORT {
+        ModbusTransport::Rtu => {
+            let mut frame = [0u8; 8];
+            let len = pdu_with_checksum(pdu, &mut frame);
+            let _ = uart.write(&frame[..len]);
+        }
+        ModbusTransport::Ascii => {
+            let mut ascii_frame = [0u8; 48];
+            let len = build_ascii_frame(pdu, &mut ascii_frame);
+            let _ = uart.write(&ascii_frame[..len]);
+        }
+    }
+    let _ = uart.flush();
+    sleep(DE_RE_TURNAROUND);
+    set_driver_enable(de_pin, false);
+}
+
+/// Dispatch pengecekan checksum ke CRC16 (RTU, lihat `check_crc`) atau LRC
+/// (ASCII, lihat `check_lrc`) sesuai `TRANSPORT` -- dipakai di semua titik
+/// yang sebelumnya panggil `check_crc` langsung, supaya logika register
+/// (poll_slave, read_scale_register, write_register) tidak perlu tahu
+/// framing yang sedang aktif.
+fn verify_checksum(frame: &[u8]) -> bool {
+    match TRANSPORT {
+        ModbusTransport::Rtu => check_crc(frame),
+        ModbusTransport::Ascii => check_lrc(frame),
+    }
+}
+
+/// Setara `check_crc` tapi untuk transport ASCII: byte terakhir frame
+/// (representasi biner setelah decode hex) adalah LRC atas byte-byte
+/// sebelumnya, bukan CRC16 dua byte little-endian seperti RTU.
+fn check_lrc(frame: &[u8]) -> bool {
+    if frame.is_empty() {
+        return false;
+    }
+    let idx = frame.len() - 1;
+    frame[idx] == lrc(&frame[..idx])
+}
 
 #[esp_hal::main]
 fn main() -> ! {
     let p = esp_hal::init(Config::default());
 
+    println!("FW:{}", FW_VERSION);
+
     // --- Inisialisasi UART ---
     let uart_config = UartConfig::default().with_baudrate(BAUD);
     let mut uart = Uart::new(p.UART1, uart_config)
@@ -29,79 +499,418 @@ fn main() -> ! {
         .with_rx(p.GPIO18);
 
     // --- Inisialisasi relay di pin 11 & 15 ---
-    let mut relay1 = Output::new(p.GPIO11, Level::Low, OutputConfig::default());
-    let mut relay2 = Output::new(p.GPIO15, Level::Low, OutputConfig::default());
+    // Level awal harus OFF secara logis terlepas dari polaritas board, jadi
+    // ikut RELAY_ACTIVE_LOW, bukan selalu Level::Low (lihat `set_relay`).
+    let initial_relay_level = if RELAY_ACTIVE_LOW { Level::High } else { Level::Low };
+    let mut relay1 = Output::new(p.GPIO11, initial_relay_level, OutputConfig::default());
+    let mut relay2 = Output::new(p.GPIO15, initial_relay_level, OutputConfig::default());
+    let mut relay1_on = false;
+    let mut relay2_on = false;
+    let mut relay1_changed_at = Instant::now();
+    let mut relay2_changed_at = Instant::now();
+
+    // Transceiver RS-485 di unit ini auto-direction (DE/RE diikat hardware ke
+    // TX-enable UART), jadi tidak ada GPIO driver-enable yang dipakai. Ganti
+    // `None` ini jadi `Some(Output::new(p.GPIOxx, ...))` kalau modul RS-485
+    // yang dipasang butuh DE/RE dikontrol manual.
+    let mut de_pin: Option<Output<'_>> = None;
+
+    if SELF_TEST_ON_BOOT {
+        run_self_test(&mut relay1, &mut relay2);
+    }
+
+    // SCALE_REGISTER: kalau diset, baca sekali di boot supaya satu firmware
+    // bisa dipasang ke beberapa varian sensor dengan scale factor fixed-point
+    // yang berbeda tanpa rebuild -- gagal baca (atau SCALE_REGISTER nonaktif)
+    // tetap jatuh ke divisor hardcoded SENSOR.divisors seperti sebelumnya.
+    // Hanya RH/T (indeks 0 dan 1) yang dianggap punya scale factor yang sama;
+    // divisor pressure di indeks 2 (kuantitas fisik lain) tidak disentuh.
+    let mut divisors = SENSOR.divisors;
+    if let Some(reg) = SCALE_REGISTER {
+        match read_scale_register(&mut uart, &mut de_pin, SENSOR.slave_id, reg) {
+            Some(scale) => {
+                println!("Scale register dibaca: {}", scale);
+                divisors[0] = scale;
+                divisors[1] = scale;
+            }
+            None => {
+                println!("Gagal membaca scale register, pakai divisor default {}", SENSOR.divisors[0]);
+            }
+        }
+    }
+
+    // Satu akumulator per slave di SLAVE_IDS, diindeks lewat posisinya
+    // (bukan nilai slave_id) agar tidak perlu peta/alokasi dinamis di no_std.
+    let mut accumulators = [SampleAccumulator::new(); SLAVE_IDS.len()];
+    let mut modbus_stats = ModbusStats::new();
+    let mut cycle: u32 = 0;
+
+    // Nilai T terakhir yang diketahui per slave, dipakai mengisi akumulator &
+    // cetakan pada siklus yang melewatkan pembacaan temperature (lihat
+    // `TEMP_POLL_EVERY_N_CYCLES`). `None` sampai pembacaan T pertama yang
+    // berhasil; sampel RH-saja sebelum itu dilewati, jadi nilai T yang belum
+    // diketahui tidak pernah masuk akumulator maupun cetakan.
+    let mut last_known_temp: [Option<f32>; SLAVE_IDS.len()] = [None; SLAVE_IDS.len()];
+    let mut temp_poll_cycle: u32 = 0;
+
+    // Nilai (RH, T) dari baris terakhir yang dicetak per slave, dipakai
+    // PRINT_DEDUP_ENABLED untuk menilai "berubah dari apa". `None` berarti
+    // belum pernah dicetak sehingga batch pertama selalu dicetak.
+    let mut last_printed: [Option<(f32, f32)>; SLAVE_IDS.len()] = [None; SLAVE_IDS.len()];
+
+    // --- Watchdog hardware: reset chip kalau loop macet (UART hang dsb) ---
+    let mut rtc = Rtc::new(p.LPWR);
+    rtc.rwdt.set_timeout(RwdtStage::Stage0, WDT_TIMEOUT);
+    rtc.rwdt.enable();
 
     loop {
-        let mut rh_val: Option<f32> = None;
-        let mut temp_val: Option<f32> = None;
-        
-        let mut req = [0u8; 8];
-        req[0] = SID;
-        req[1] = 0x04;
-
-        // 1. MEMBACA KELEMBAPAN (RH)
-        req[2..4].copy_from_slice(&0x0001u16.to_be_bytes());
-        req[4..6].copy_from_slice(&1u16.to_be_bytes());
-        let crc = crc16(&req[..6]);
-        req[6..8].copy_from_slice(&crc.to_le_bytes());
-        
-        let _ = uart.write(&req);
-        let _ = uart.flush();
-        let (n_rh, rx_buffer) = read_response(&mut uart);
-        
-        if n_rh >= 7 && (rx_buffer[1] & 0x80) == 0 && rx_buffer[2] == 2 && check_crc(&rx_buffer[..n_rh]) {
-            let raw_rh = u16::from_be_bytes([rx_buffer[3], rx_buffer[4]]);
-            rh_val = Some(raw_rh as f32 / 10.0);
-        }
-        
-        sleep(Duration::from_millis(100));
-
-        // 2. MEMBACA SUHU
-        req[2..4].copy_from_slice(&0x0002u16.to_be_bytes());
-        let crc2 = crc16(&req[..6]);
-        req[6..8].copy_from_slice(&crc2.to_le_bytes());
-
-        let _ = uart.write(&req);
-        let _ = uart.flush();
-        let (n_temp, rx_buffer2) = read_response(&mut uart);
-
-        if n_temp >= 7 && (rx_buffer2[1] & 0x80) == 0 && rx_buffer2[2] == 2 && check_crc(&rx_buffer2[..n_temp]) {
-            let raw_t = u16::from_be_bytes([rx_buffer2[3], rx_buffer2[4]]);
-            temp_val = Some(raw_t as f32 / 10.0);
-        }
-
-        // 3. CETAK HASIL + KONTROL RELAY
-        match (rh_val, temp_val) {
-            (Some(rh), Some(temp)) => {
-                println!("RH:{:.1},T:{:.1}", rh, temp);
-
-                if temp > 27.0 {
-                    relay1.set_high();
-                    relay2.set_high();
-                    println!("Relay ON (Temp {:.1} > 27)", temp);
-                } else {
-                    relay1.set_low();
-                    relay2.set_low();
-                    println!("Relay OFF (Temp {:.1} <= 27)", temp);
+        rtc.rwdt.feed();
+
+        // RH (chamber kami berubah cepat) tetap ditanya tiap siklus; T hanya
+        // diikutkan di kelipatan TEMP_POLL_EVERY_N_CYCLES -- hanya berlaku
+        // untuk sensor RH+T standar (register_count == 2), model dengan
+        // register tambahan (mis. pressure) tetap dibaca sekaligus seperti
+        // sebelumnya supaya tidak ada kasus divisor-offset yang belum diuji.
+        let read_temp_this_cycle =
+            SENSOR.register_count != 2 || temp_poll_cycle % TEMP_POLL_EVERY_N_CYCLES == 0;
+
+        // Gilir semua slave di bus: satu yang tidak merespon hanya melewatkan
+        // baris cetaknya sendiri (lihat poll_slave), tidak menahan giliran
+        // slave berikutnya.
+        for (idx, &slave_id) in SLAVE_IDS.iter().enumerate() {
+            let reading = if read_temp_this_cycle {
+                poll_slave(&mut uart, &mut de_pin, slave_id, SENSOR.start_register, SENSOR.register_count, 0, &divisors)
+            } else {
+                // Hanya register RH (quantity=1) -- request lebih singkat,
+                // itulah penghematan bus traffic yang dicari.
+                poll_slave(&mut uart, &mut de_pin, slave_id, SENSOR.start_register, 1, 0, &divisors)
+            };
+            modbus_stats.record(reading.is_some());
+
+            // Sampel gagal (CRC/timeout) tidak pernah masuk akumulator, jadi
+            // rata-rata selalu dari SAMPLES_PER_REPORT pembacaan valid
+            // berurutan saja.
+            if let Some(mut values) = reading {
+                if SENSOR.register_count == 2 {
+                    if read_temp_this_cycle {
+                        last_known_temp[idx] = Some(values[1]);
+                    } else {
+                        // Slot T tidak ditanya siklus ini (`values[1]` masih
+                        // 0.0 dari poll_slave) -- isi dengan nilai terakhir
+                        // yang diketahui supaya rata-rata & cetakan tidak
+                        // bolong/turun ke nol.
+                        match last_known_temp[idx] {
+                            Some(t) => values[1] = t,
+                            None => continue,
+                        }
+                    }
+                }
+                if let Some(avg) = accumulators[idx].push(&values) {
+                    let avg_rh = avg[0];
+                    let avg_temp = avg[1];
+
+                    let should_print = !PRINT_DEDUP_ENABLED
+                        || match last_printed[idx] {
+                            Some((last_rh, last_temp)) => {
+                                (avg_rh - last_rh).abs() >= PRINT_DEDUP_DELTA
+                                    || (avg_temp - last_temp).abs() >= PRINT_DEDUP_DELTA
+                            }
+                            None => true,
+                        };
+                    if should_print {
+                        if SENSOR.register_count >= 3 {
+                            println!("SID:{},RH:{:.1},T:{:.1},P:{:.1}", slave_id, avg_rh, avg_temp, avg[2]);
+                        } else {
+                            println!("SID:{},RH:{:.1},T:{:.1}", slave_id, avg_rh, avg_temp);
+                        }
+                        last_printed[idx] = Some((avg_rh, avg_temp));
+                    }
+
+                    // Kontrol relay tetap mengevaluasi tiap batch terlepas dari
+                    // should_print di atas -- dedup hanya memotong baris cetak,
+                    // bukan siklus kontrol. Mengacu ke slave acuan (SID pertama di
+                    // SLAVE_IDS) supaya unit lama yang hanya punya satu sensor
+                    // tidak berubah perilakunya.
+                    if slave_id == SLAVE_IDS[0] {
+                        let want1 = relay_next_state(relay1_on, avg_temp, &RELAY1_CONFIG);
+                        if want1 != relay1_on && relay1_changed_at.elapsed() >= RELAY_MIN_DWELL {
+                            relay1_on = want1;
+                            relay1_changed_at = Instant::now();
+                            set_relay(&mut relay1, relay1_on);
+                            println!("Relay1 {} (Temp {:.1})", if relay1_on { "ON" } else { "OFF" }, avg_temp);
+                        }
+
+                        let want2 = relay_next_state(relay2_on, avg_temp, &RELAY2_CONFIG);
+                        if want2 != relay2_on && relay2_changed_at.elapsed() >= RELAY_MIN_DWELL {
+                            relay2_on = want2;
+                            relay2_changed_at = Instant::now();
+                            set_relay(&mut relay2, relay2_on);
+                            println!("Relay2 {} (Temp {:.1})", if relay2_on { "ON" } else { "OFF" }, avg_temp);
+                        }
+                    }
                 }
-            },
-            _ => {
-                // Tidak mencetak apa-apa jika gagal agar tidak mengganggu Python
             }
+            // Gagal/tidak merespon: tidak ada baris data `SID:..,RH:..` yang
+            // dicetak; hanya baris diagnosa [NO RESPONSE]/[BAD FRAME] dari poll_slave.
+        }
+        cycle += 1;
+        temp_poll_cycle += 1;
+        if cycle >= STATS_REPORT_EVERY {
+            modbus_stats.report_and_reset();
+            cycle = 0;
         }
+
         sleep(Duration::from_millis(2000));
     }
 }
 
-// Fungsi helper sama seperti sebelumnya
-fn read_response(uart: &mut Uart<'_, impl DriverMode>) -> (usize, [u8; 32]) {
+/// Baca `quantity` register berurutan mulai `start_register` dari satu
+/// slave, retry beberapa kali sebelum menyerah. Dipisah dari `main` agar
+/// bisa dipanggil bergilir untuk tiap SID di `SLAVE_IDS` tanpa menduplikasi
+/// logika retry/CRC. `divisor_offset` menggeser indeks ke `divisors` supaya
+/// pembacaan parsial yang tidak dimulai dari register pertama `SENSOR` tetap
+/// memakai divisor yang benar; pemanggil di `main` selalu memberi 0 (pembacaan
+/// RH-saja di `TEMP_POLL_EVERY_N_CYCLES` dimulai dari register pertama). `divisors` sendiri diteruskan dari
+/// `main` (bukan langsung `SENSOR.divisors`) supaya `SCALE_REGISTER` bisa
+/// menimpanya secara runtime setelah dibaca sekali dari sensor saat boot.
+/// Hasil diskalakan per register; slot di luar `quantity` pada array hasil
+/// selalu 0.0 dan harus diabaikan pemanggil.
+fn poll_slave(
+    uart: &mut Uart<'_, impl DriverMode>,
+    de_pin: &mut Option<Output<'_>>,
+    slave_id: u8,
+    start_register: u16,
+    quantity: u16,
+    divisor_offset: usize,
+    divisors: &[f32; MAX_REGISTERS],
+) -> Option<[f32; MAX_REGISTERS]> {
+    // `byte_count` turun dari `quantity` (bukan magic number 2), jadi
+    // `expected_len` otomatis ikut benar untuk pembacaan satu, dua, atau tiga
+    // register -- kita lalu memvalidasi byte-count yang dikembalikan slave
+    // (`rx_buffer[2]`) terhadap nilai ini sebelum menganggap frame-nya valid.
+    let byte_count = (quantity * 2) as usize;
+    let expected_len = 3 + byte_count + checksum_len();
+    let mut pdu = [0u8; 6];
+    pdu[0] = slave_id;
+    pdu[1] = SENSOR.read_function;
+    pdu[2..4].copy_from_slice(&start_register.to_be_bytes());
+    pdu[4..6].copy_from_slice(&quantity.to_be_bytes());
+
+    let timeout = response_timeout(BAUD, expected_len);
+    const MAX_ATTEMPTS: u8 = 3;
+    for attempt in 0..MAX_ATTEMPTS {
+        send_pdu(uart, de_pin, &pdu);
+        let (n, rx_buffer) = read_response(uart, timeout);
+
+        // Bus benar-benar diam (0 byte masuk sebelum timeout) itu beda masalah
+        // dari frame yang datang tapi rusak: yang pertama biasanya wiring/
+        // termination A-B, yang kedua biasanya noise/CRC. Campur keduanya jadi
+        // "gagal" yang sama dulu pernah bikin kami salah diagnosa kabel lepas
+        // sebagai masalah CRC.
+        if n == 0 {
+            println!("SID:{} [NO RESPONSE]", slave_id);
+        } else if n >= expected_len && rx_buffer[0] != slave_id {
+            // Bus multi-drop: byte pertama respons bukan SID yang kita tanya,
+            // berarti ini gesekan dari slave lain (mis. respons lama yang
+            // baru nyangkut, atau collision). Jangan pernah dipakai sebagai
+            // bacaan SID ini walau function code dan byte-count-nya kebetulan cocok.
+            println!("SID:{} [BAD FRAME] SID respons tidak cocok (dapat {})", slave_id, rx_buffer[0]);
+        } else if let Some(code) = modbus_exception(&rx_buffer[..n]) {
+            println!("SID:{} Modbus exception: {}", slave_id, exception_name(code));
+            break;
+        } else if n >= expected_len && rx_buffer[1] == SENSOR.read_function && rx_buffer[2] as usize == byte_count {
+            if verify_checksum(&rx_buffer[..n]) {
+                let mut values = [0.0f32; MAX_REGISTERS];
+                for i in 0..quantity as usize {
+                    let raw = u16::from_be_bytes([rx_buffer[3 + i * 2], rx_buffer[4 + i * 2]]);
+                    values[i] = raw as f32 / divisors[divisor_offset + i];
+                }
+                return Some(values);
+            } else {
+                println!("SID:{} [BAD FRAME] CRC mismatch", slave_id);
+                if CRC_DEBUG {
+                    let (received, calculated) = crc_debug(&rx_buffer[..n]);
+                    println!("SID:{} CRC mismatch: received=0x{:04X} calculated=0x{:04X}", slave_id, received, calculated);
+                }
+            }
+        } else {
+            println!("SID:{} [BAD FRAME] unexpected response ({} byte)", slave_id, n);
+        }
+
+        if attempt + 1 < MAX_ATTEMPTS {
+            sleep(Duration::from_millis(50));
+        }
+    }
+    None
+}
+
+/// Baca satu holding register (fungsi 0x03) sekali saat boot untuk
+/// menentukan scale factor RH/T secara dinamis lewat `SCALE_REGISTER`.
+/// Beberapa percobaan (sama seperti `poll_slave`) karena ini tetap lewat bus
+/// RS-485 yang bisa kena noise saat commissioning; register bernilai 0 juga
+/// ditolak (pembagi nol tidak masuk akal) supaya sensor yang belum
+/// diprovisioning tidak diam-diam mematikan skala RH/T.
+fn read_scale_register(uart: &mut Uart<'_, impl DriverMode>, de_pin: &mut Option<Output<'_>>, slave_id: u8, reg: u16) -> Option<f32> {
+    let expected_len = 3 + 2 + checksum_len(); // slave, func, byte_count, 1 register, checksum
+    let mut pdu = [0u8; 6];
+    pdu[0] = slave_id;
+    pdu[1] = 0x03;
+    pdu[2..4].copy_from_slice(&reg.to_be_bytes());
+    pdu[4..6].copy_from_slice(&1u16.to_be_bytes());
+
+    let timeout = response_timeout(BAUD, expected_len);
+    const MAX_ATTEMPTS: u8 = 3;
+    for attempt in 0..MAX_ATTEMPTS {
+        send_pdu(uart, de_pin, &pdu);
+        let (n, rx_buffer) = read_response(uart, timeout);
+
+        if n >= expected_len
+            && rx_buffer[0] == slave_id
+            && rx_buffer[1] == 0x03
+            && rx_buffer[2] as usize == 2
+            && verify_checksum(&rx_buffer[..n])
+        {
+            let raw = u16::from_be_bytes([rx_buffer[3], rx_buffer[4]]);
+            if raw > 0 {
+                return Some(raw as f32);
+            }
+            println!("SID:{} scale register bernilai 0, diabaikan", slave_id);
+            break;
+        }
+
+        if attempt + 1 < MAX_ATTEMPTS {
+            sleep(Duration::from_millis(50));
+        }
+    }
+    None
+}
+
+/// Tulis satu register lewat fungsi 0x06 (write single register) dan
+/// verifikasi slave meng-echo balik frame yang sama persis (request dan
+/// response fungsi 0x06 identik menurut spec). Dipakai untuk mengirim
+/// setpoint ke perangkat Modbus lain di bus yang sama, misal menutup loop
+/// kontrol berdasarkan suhu yang dibaca dari sensor.
+fn write_register(uart: &mut Uart<'_, impl DriverMode>, de_pin: &mut Option<Output<'_>>, slave_id: u8, reg: u16, value: u16) -> bool {
+    let mut pdu = [0u8; 6];
+    pdu[0] = slave_id;
+    pdu[1] = 0x06;
+    pdu[2..4].copy_from_slice(&reg.to_be_bytes());
+    pdu[4..6].copy_from_slice(&value.to_be_bytes());
+
+    send_pdu(uart, de_pin, &pdu);
+    // Respons 0x06 selalu meng-echo request (pdu + checksum) persis, jadi
+    // `expected` di bawah dipakai sekaligus sebagai panjang dan isi yang
+    // diharapkan, terlepas dari transport yang aktif.
+    let mut expected = [0u8; 8];
+    let expected_len = pdu_with_checksum(&pdu, &mut expected);
+    let (n, rx_buffer) = read_response(uart, response_timeout(BAUD, expected_len));
+
+    if let Some(code) = modbus_exception(&rx_buffer[..n]) {
+        println!("SID:{} write_register exception: {}", slave_id, exception_name(code));
+        return false;
+    }
+    n == expected_len && verify_checksum(&rx_buffer[..n]) && rx_buffer[..n] == expected[..expected_len]
+}
+
+/// Dispatch ke `read_response_rtu` atau `read_response_ascii` sesuai
+/// `TRANSPORT` -- titik tunggal yang dipanggil `poll_slave`,
+/// `read_scale_register`, dan `write_register` supaya ketiganya tidak perlu
+/// tahu framing mana yang sedang aktif.
+fn read_response(uart: &mut Uart<'_, impl DriverMode>, timeout: Duration) -> (usize, [u8; 32]) {
+    match TRANSPORT {
+        ModbusTransport::Rtu => read_response_rtu(uart, timeout),
+        ModbusTransport::Ascii => read_response_ascii(uart, timeout),
+    }
+}
+
+/// Akumulasi byte sampai frame Modbus RTU lengkap (panjang diturunkan dari
+/// function code + byte count, lihat `expected_frame_len`) atau `timeout`
+/// habis. `uart.read` bersifat non-blocking (bisa mengembalikan 0 byte),
+/// jadi satu panggilan saja sering menangkap frame yang masih separuh jalan
+/// di kabel — itu yang sebelumnya membuat `check_crc` gagal secara acak.
+fn read_response_rtu(uart: &mut Uart<'_, impl DriverMode>, timeout: Duration) -> (usize, [u8; 32]) {
     let mut rx_buffer = [0u8; 32];
-    if let Ok(bytes_read) = uart.read(&mut rx_buffer) {
-        (bytes_read, rx_buffer)
-    } else {
-        (0, rx_buffer)
+    let mut n = 0usize;
+    let start = Instant::now();
+
+    loop {
+        if let Ok(read) = uart.read(&mut rx_buffer[n..]) {
+            n += read;
+        }
+
+        if let Some(expected) = expected_frame_len(&rx_buffer[..n]) {
+            if n >= expected {
+                break;
+            }
+        }
+
+        if n >= rx_buffer.len() || start.elapsed() >= timeout {
+            break;
+        }
     }
+
+    (n, rx_buffer)
+}
+
+/// Setara `read_response_rtu` tapi untuk frame ASCII: tidak ada panjang yang
+/// bisa diprediksi dari function code karena payload-nya hex-encoded, jadi
+/// kita akumulasi sampai byte `\n` penutup (akhir `:...\r\n`) atau timeout,
+/// lalu strip `:` depan dan `\r\n` belakang dan decode hex-nya lewat
+/// `decode_ascii_hex` supaya hasilnya (biner, termasuk checksum LRC)
+/// berbentuk sama seperti keluaran `read_response_rtu`.
+fn read_response_ascii(uart: &mut Uart<'_, impl DriverMode>, timeout: Duration) -> (usize, [u8; 32]) {
+    let mut ascii_buffer = [0u8; 64];
+    let mut n = 0usize;
+    let start = Instant::now();
+
+    loop {
+        if let Ok(read) = uart.read(&mut ascii_buffer[n..]) {
+            n += read;
+        }
+
+        if n > 0 && ascii_buffer[n - 1] == b'\n' {
+            break;
+        }
+
+        if n >= ascii_buffer.len() || start.elapsed() >= timeout {
+            break;
+        }
+    }
+
+    let mut rx_buffer = [0u8; 32];
+    if n < 5 || ascii_buffer[0] != b':' || ascii_buffer[n - 2] != b'\r' || ascii_buffer[n - 1] != b'\n' {
+        return (0, rx_buffer);
+    }
+    match decode_ascii_hex(&ascii_buffer[1..n - 2], &mut rx_buffer) {
+        Some(len) => (len, rx_buffer),
+        None => (0, rx_buffer),
+    }
+}
+
+/// Panjang total frame yang diharapkan begitu cukup byte awal sudah masuk,
+/// atau `None` kalau belum cukup untuk menentukannya. Exception frame selalu
+/// 5 byte (slave, func|0x80, kode, crc lo/hi); frame fungsi 0x03/0x04 normal
+/// (holding/input register) sama-sama 3 byte header + byte count data + 2
+/// byte CRC.
+fn expected_frame_len(frame: &[u8]) -> Option<usize> {
+    if frame.len() < 2 {
+        return None;
+    }
+    if (frame[1] & 0x80) != 0 {
+        return Some(5);
+    }
+    if frame[1] == 3 || frame[1] == 4 {
+        if frame.len() < 3 {
+            return None;
+        }
+        return Some(3 + frame[2] as usize + 2);
+    }
+    if frame[1] == 6 {
+        // Response 0x06 meng-echo seluruh request: slave, func, reg hi/lo,
+        // value hi/lo, crc lo/hi -> selalu 8 byte.
+        return Some(8);
+    }
+    None
 }
 fn crc16(data: &[u8]) -> u16 { 
     let mut crc = 0xFFFFu16; 
@@ -117,12 +926,50 @@ fn crc16(data: &[u8]) -> u16 {
     } 
     crc 
 }
-fn check_crc(frame: &[u8]) -> bool { 
-    if frame.len() < 3 { return false; } 
-    let crc_index = frame.len() - 2; 
-    let received_crc = u16::from_le_bytes([frame[crc_index], frame[crc_index + 1]]); 
-    let calculated_crc = crc16(&frame[..crc_index]); 
-    received_crc == calculated_crc 
+// Jika bit tinggi function code menyala, slave mengembalikan exception; byte
+// ketiga frame adalah kode exception-nya (1=illegal function, dst).
+fn modbus_exception(frame: &[u8]) -> Option<u8> {
+    if frame.len() < 3 {
+        return None;
+    }
+    if (frame[1] & 0x80) != 0 {
+        Some(frame[2])
+    } else {
+        None
+    }
+}
+
+fn exception_name(code: u8) -> &'static str {
+    match code {
+        1 => "illegal function",
+        2 => "illegal data address",
+        3 => "illegal data value",
+        4 => "slave device failure",
+        5 => "acknowledge",
+        6 => "slave device busy",
+        _ => "unknown exception",
+    }
+}
+
+fn check_crc(frame: &[u8]) -> bool {
+    if frame.len() < 3 { return false; }
+    let crc_index = frame.len() - 2;
+    let received_crc = u16::from_le_bytes([frame[crc_index], frame[crc_index + 1]]);
+    let calculated_crc = crc16(&frame[..crc_index]);
+    received_crc == calculated_crc
+}
+
+/// Sama seperti `check_crc` tapi mengembalikan (received, calculated) mentah
+/// alih-alih bool, supaya frame yang gagal bisa didiagnosis (mis. slave yang
+/// mengirim CRC big-endian) tanpa harus menebak dari sisi kode.
+fn crc_debug(frame: &[u8]) -> (u16, u16) {
+    if frame.len() < 3 {
+        return (0, 0);
+    }
+    let crc_index = frame.len() - 2;
+    let received = u16::from_le_bytes([frame[crc_index], frame[crc_index + 1]]);
+    let calculated = crc16(&frame[..crc_index]);
+    (received, calculated)
 }
 #[inline(always)]
 fn sleep(dur: Duration) { 
@@ -0,0 +1,17 @@
+//! Mengemit `VERGEN_BUILD_TIMESTAMP`/`VERGEN_GIT_SHA` sebagai env var compile-time
+//! (dibaca lewat `env!()` di `main.rs`) supaya `--version` bisa menyebut commit
+//! dan waktu build tanpa operator harus `git log` manual di unit lapangan.
+use vergen::{BuildBuilder, Emitter};
+use vergen_gitcl::GitclBuilder;
+
+fn main() {
+    let build = BuildBuilder::all_build().expect("BuildBuilder::all_build gagal");
+    let git = GitclBuilder::all_git().expect("GitclBuilder::all_git gagal");
+    Emitter::default()
+        .add_instructions(&build)
+        .expect("menambahkan instruksi build vergen gagal")
+        .add_instructions(&git)
+        .expect("menambahkan instruksi git vergen gagal")
+        .emit()
+        .expect("emit vergen gagal");
+}
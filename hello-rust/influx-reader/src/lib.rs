@@ -0,0 +1,1001 @@
+//! Pipeline parsing, escaping, dan pairing RH/T yang dipakai biner
+//! `serial_to_influx`. Dipisah dari `main.rs` supaya bisa diuji dan dipakai
+//! ulang (mis. dari tool lain yang mau menguraikan baris sensor yang sama)
+//! tanpa menyalin kode dan tanpa ikut menyeret I/O (serial, HTTP, MQTT).
+
+use anyhow::{anyhow, Context, Result};
+use log::{debug, warn};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde_json::Value;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+
+// ========================= Regex input serial =========================
+static RH_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?i)\bRH\b\s*=\s*([0-9]+(?:\.[0-9]+)?)\s*%").unwrap()
+});
+static T_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?i)\bT\b\s*=\s*([0-9]+(?:\.[0-9]+)?)\s*°?\s*C").unwrap()
+});
+// Format ringkas yang dicetak firmware: `RH:25.3,T:26.1`.
+static FIRMWARE_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?i)\bRH:([0-9]+(?:\.[0-9]+)?),T:([0-9]+(?:\.[0-9]+)?)").unwrap()
+});
+// Firmware multi-slave mencantumkan `SID:n` di depan baris; sumber lain
+// boleh memakai token generik `source=...`. Kalau salah satu ada, dia
+// menimpa `TAG_SOURCE` default per baris, supaya satu bridge bisa
+// membedakan titik data per sensor fisik alih-alih menimpa satu sama lain.
+static SOURCE_TAG_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?i)\bSID:([A-Za-z0-9_.-]+)|\bsource=([A-Za-z0-9_.-]+)").unwrap()
+});
+// Firmware mencetak aktuasi relay sebagai `Relay1 ON (Temp 23.4)` / `Relay2 OFF
+// (...)`. Nomor relay opsional (unit lama dengan satu relay cukup cetak
+// `Relay ON`/`Relay OFF`).
+static RELAY_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?i)\bRelay(\d*)\s+(ON|OFF)\b").unwrap()
+});
+// Banner boot firmware: `FW:1.2.3`.
+static FW_VERSION_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?i)\bFW:([0-9A-Za-z_.-]+)").unwrap()
+});
+
+pub struct Pending {
+    rh: Option<f64>,
+    rh_at: Option<Instant>,
+    t: Option<f64>,
+    t_at: Option<Instant>,
+}
+
+impl Pending {
+    pub fn new() -> Self {
+        Self { rh: None, rh_at: None, t: None, t_at: None }
+    }
+
+    /// Buang nilai yang sudah lebih tua dari `timeout` agar tidak dipasangkan
+    /// dengan partner baru yang datang jauh kemudian.
+    fn expire_stale(&mut self, timeout: Duration) {
+        if let Some(at) = self.rh_at {
+            if at.elapsed() > timeout {
+                self.rh = None;
+                self.rh_at = None;
+            }
+        }
+        if let Some(at) = self.t_at {
+            if at.elapsed() > timeout {
+                self.t = None;
+                self.t_at = None;
+            }
+        }
+    }
+
+    /// Kosongkan kedua paruh pasangan setelah keduanya terpakai membentuk satu titik.
+    pub fn clear(&mut self) {
+        self.rh = None;
+        self.t = None;
+    }
+}
+
+impl Default for Pending {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// ========================= Helper line-protocol =========================
+pub fn append_timestamp(line: String, ts: Option<i128>) -> String {
+    match ts {
+        Some(ts) => format!("{} {}", line, ts),
+        None => line,
+    }
+}
+
+pub fn escape_measurement(s: &str) -> String {
+    s.replace(',', r"\,").replace(' ', r"\ ").replace('=', r"\=")
+}
+pub fn escape_tag_key_or_value(s: &str) -> String {
+    s.replace(',', r"\,").replace(' ', r"\ ").replace('=', r"\=")
+}
+pub fn escape_field_key(s: &str) -> String {
+    s.replace(',', r"\,").replace(' ', r"\ ").replace('=', r"\=")
+}
+pub fn quote_string_field(s: &str) -> String {
+    let escaped = s.replace('\\', r"\\").replace('"', r#"\""#);
+    format!("\"{}\"", escaped)
+}
+
+/// Format angka sesuai spec line-protocol: integer dapat suffix `i` (lebih
+/// ringkas untuk counter di Influx), float seperti biasa. Hanya dipakai saat
+/// `emit_integers` aktif dan nilainya benar-benar bulat serta muat di i64 —
+/// kalau tidak, kita tetap tulis float supaya tidak membelah satu seri
+/// antara tipe int dan float (Influx menolak penulisan begitu).
+pub fn format_number_field(n: f64, emit_integers: bool) -> String {
+    if emit_integers && n.fract() == 0.0 && n >= i64::MIN as f64 && n <= i64::MAX as f64 {
+        format!("{}i", n as i64)
+    } else {
+        format!("{}", n)
+    }
+}
+
+/// Alasan spesifik kenapa satu baris gagal diparse sebagai field generik.
+/// Dulu ketiga parser cuma balikin `Option`, jadi log jatuh ke "bukan format
+/// RH/T" yang sama persis untuk JSON rusak, KV tanpa `=`, maupun baris
+/// kosong -- menyulitkan diagnosa output sensor yang aneh.
+#[derive(Debug)]
+pub enum ParseError {
+    InvalidJson(serde_json::Error),
+    NotAJsonObject,
+    NoFields,
+    Empty,
+    NotANumber,
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseError::InvalidJson(e) => write!(f, "bukan JSON valid: {}", e),
+            ParseError::NotAJsonObject => write!(f, "JSON valid tapi bukan object"),
+            ParseError::NoFields => write!(f, "tidak ada field yang bisa diekstrak"),
+            ParseError::Empty => write!(f, "baris kosong"),
+            ParseError::NotANumber => write!(f, "bukan angka tunggal"),
+        }
+    }
+}
+
+pub fn parse_json_fields(s: &str, emit_integers: bool, allowed_fields: Option<&[String]>) -> Result<Vec<(String, String)>, ParseError> {
+    let v: Value = serde_json::from_str(s).map_err(ParseError::InvalidJson)?;
+    let obj = v.as_object().ok_or(ParseError::NotAJsonObject)?;
+    let mut fields = Vec::new();
+    collect_json_fields("", obj, emit_integers, allowed_fields, &mut fields);
+    if fields.is_empty() { Err(ParseError::NoFields) } else { Ok(fields) }
+}
+
+/// Kumpulkan field skalar dari `obj` ke `fields`, mem-flatten object
+/// bersarang jadi key bertitik (`meta.fw`) secara rekursif. Array dilewati
+/// dengan log debug karena tidak ada representasi line-protocol yang jelas
+/// untuknya -- daripada membuat seluruh baris gagal parse gara-gara satu
+/// field array, field skalar lain tetap terambil. `allowed_fields`, kalau
+/// diset, membuang key daun (bukan key container nested) yang tidak ada di
+/// daftar, supaya firmware yang salah tingkah tidak bisa menambah field
+/// high-cardinality sembarangan ke Influx.
+fn collect_json_fields(prefix: &str, obj: &serde_json::Map<String, Value>, emit_integers: bool, allowed_fields: Option<&[String]>, fields: &mut Vec<(String, String)>) {
+    for (k, val) in obj {
+        let key = if prefix.is_empty() { k.clone() } else { format!("{}.{}", prefix, k) };
+        if let Some(nested) = val.as_object() {
+            collect_json_fields(&key, nested, emit_integers, allowed_fields, fields);
+            continue;
+        }
+        if let Some(allowed) = allowed_fields {
+            if !allowed.iter().any(|a| a == &key) {
+                debug!("Field JSON '{}' tidak ada di ALLOWED_FIELDS, dibuang", key);
+                continue;
+            }
+        }
+        if let Some(n) = val.as_f64() {
+            fields.push((escape_field_key(&key), format_number_field(n, emit_integers)));
+        } else if let Some(b) = val.as_bool() {
+            fields.push((escape_field_key(&key), format!("{}", b)));
+        } else if let Some(st) = val.as_str() {
+            fields.push((escape_field_key(&key), quote_string_field(st)));
+        } else if val.is_array() {
+            debug!("Field JSON '{}' adalah array, dilewati (tidak ada representasi line-protocol)", key);
+        }
+    }
+}
+
+/// Pecah `s` pada `,`/spasi seperti sebelumnya, tapi tidak memecah di dalam
+/// span `"..."` supaya `status="all good"` tetap satu token alih-alih
+/// terpotong jadi `status="all` dan `good"`.
+fn tokenize_kv_pairs(s: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    let mut in_quotes = false;
+
+    for c in s.chars() {
+        if c == '"' {
+            in_quotes = !in_quotes;
+            current.push(c);
+        } else if (c == ',' || c == ' ') && !in_quotes {
+            if !current.is_empty() {
+                tokens.push(std::mem::take(&mut current));
+            }
+        } else {
+            current.push(c);
+        }
+    }
+    if !current.is_empty() {
+        tokens.push(current);
+    }
+    tokens
+}
+
+pub fn parse_kv_fields(s: &str, emit_integers: bool, allowed_fields: Option<&[String]>) -> Result<Vec<(String, String)>, ParseError> {
+    let parts = tokenize_kv_pairs(s);
+    if parts.is_empty() { return Err(ParseError::Empty); }
+    let mut got = Vec::new();
+    for p in parts {
+        if let Some(eq) = p.find('=') {
+            let key = &p[..eq];
+            let val = p[eq + 1..].trim();
+            if key.is_empty() { continue; }
+            // `a=b=c`: ambil semuanya setelah '=' pertama sebagai value dulu
+            // pernah terasa masuk akal, tapi maksud token seperti itu
+            // ambigu (value literal "b=c"? typo pemisah?) -- lebih aman
+            // tolak dengan warning daripada menebak, supaya operator sadar
+            // ada baris sensor yang formatnya tidak seperti yang kita kira.
+            if val.contains('=') {
+                warn!("KV token '{}' punya lebih dari satu '=', dilewati", p);
+                continue;
+            }
+            let key = key.trim();
+            if let Some(allowed) = allowed_fields {
+                if !allowed.iter().any(|a| a == key) {
+                    debug!("Field KV '{}' tidak ada di ALLOWED_FIELDS, dibuang", key);
+                    continue;
+                }
+            }
+            let key_esc = escape_field_key(key);
+            if val.len() >= 2 && val.starts_with('"') && val.ends_with('"') {
+                let inner = &val[1..val.len() - 1];
+                let unescaped = inner.replace(r#"\""#, "\"").replace(r"\\", "\\");
+                got.push((key_esc, quote_string_field(&unescaped)));
+            } else if let Ok(n) = val.parse::<f64>() {
+                got.push((key_esc, format_number_field(n, emit_integers)));
+            } else if val.eq_ignore_ascii_case("true") || val.eq_ignore_ascii_case("false") {
+                got.push((key_esc, val.to_ascii_lowercase()));
+            } else {
+                got.push((key_esc, quote_string_field(val)));
+            }
+        }
+    }
+    if got.is_empty() { Err(ParseError::NoFields) } else { Ok(got) }
+}
+
+pub fn parse_single_number(s: &str) -> Result<Vec<(String, String)>, ParseError> {
+    let trimmed = s.trim();
+    if trimmed.is_empty() { return Err(ParseError::Empty); }
+    // `f64::parse` sebenarnya sudah menerima '+' di depan, tapi distrip
+    // eksplisit di sini supaya niatnya jelas dibaca dan tidak diam-diam
+    // bergantung pada detail parser standar library.
+    let normalized = trimmed.strip_prefix('+').unwrap_or(trimmed);
+    normalized.parse::<f64>()
+        .map(|n| vec![(escape_field_key("value"), format!("{}", n))])
+        .map_err(|_| ParseError::NotANumber)
+}
+
+/// Parse `FIELD_MAP` (`0:temperature,1:humidity,2:pressure`) jadi pasangan
+/// indeks posisi -> nama field, dipakai `parse_positional_csv_fields` untuk
+/// menamai CSV mentah dari firmware yang tidak mengirim key sama sekali
+/// (lihat komentar di sana). Pasangan dengan indeks yang gagal diparse atau
+/// nama kosong dibuang diam-diam, sama seperti `parse_field_units`.
+pub fn parse_field_map(s: &str) -> Vec<(usize, String)> {
+    s.split(',')
+        .filter_map(|pair| {
+            let pair = pair.trim();
+            let colon = pair.find(':')?;
+            let idx = pair[..colon].trim().parse::<usize>().ok()?;
+            let name = pair[colon + 1..].trim();
+            if name.is_empty() {
+                return None;
+            }
+            Some((idx, name.to_string()))
+        })
+        .collect()
+}
+
+/// Parse baris CSV posisional (`26.1,55.0,101.3`, tanpa key) jadi field
+/// bernama lewat `field_map` (lihat `parse_field_map`) -- dipakai firmware
+/// yang mencetak nilai mentah tanpa label karena urutannya sendiri sudah
+/// menjadi kontrak implisit dengan influx-reader. Indeks yang tidak ada di
+/// `field_map`, atau token yang bukan angka, dibuang diam-diam; `Err` hanya
+/// kalau TIDAK SATU PUN token termapping berhasil diparse, supaya baris yang
+/// benar-benar bukan CSV jatuh ke fallback Single Number/Raw seperti biasa.
+pub fn parse_positional_csv_fields(s: &str, field_map: &[(usize, String)], emit_integers: bool) -> Result<Vec<(String, String)>, ParseError> {
+    if field_map.is_empty() {
+        return Err(ParseError::Empty);
+    }
+    let tokens: Vec<&str> = s.trim().split(',').map(|t| t.trim()).collect();
+    let mut fields = Vec::new();
+    for (idx, name) in field_map {
+        let Some(token) = tokens.get(*idx) else { continue };
+        let Ok(n) = token.parse::<f64>() else { continue };
+        fields.push((escape_field_key(name), format_number_field(n, emit_integers)));
+    }
+    if fields.is_empty() {
+        return Err(ParseError::NotANumber);
+    }
+    Ok(fields)
+}
+
+/// Parse `INFLUX_EXTRA_TAGS` (`k1=v1,k2=v2`) jadi pasangan tag yang sudah
+/// di-escape, supaya situs pemanggil tidak perlu escape ulang setiap baris.
+pub fn parse_extra_tags(s: &str) -> Vec<(String, String)> {
+    s.split(',')
+        .filter_map(|pair| {
+            let pair = pair.trim();
+            let eq = pair.find('=')?;
+            let key = pair[..eq].trim();
+            let val = pair[eq + 1..].trim();
+            if key.is_empty() || val.is_empty() {
+                return None;
+            }
+            Some((escape_tag_key_or_value(key), escape_tag_key_or_value(val)))
+        })
+        .collect()
+}
+
+/// Gabungkan tag statis jadi suffix line-protocol siap tempel, misal
+/// `,site=plantA,line=3`, atau string kosong kalau tidak ada tag.
+pub fn extra_tags_suffix(tags: &[(String, String)]) -> String {
+    tags.iter().map(|(k, v)| format!(",{}={}", k, v)).collect()
+}
+
+/// Parse `FIELD_UNITS` (`field1:unit1,field2:unit2`) jadi pasangan field->unit.
+/// Dipakai untuk tag `<field>_unit=<unit>` pada titik RH/T supaya satuan bisa
+/// di-query langsung dari InfluxDB tanpa hardcode di dashboard Grafana.
+pub fn parse_field_units(s: &str) -> Vec<(String, String)> {
+    s.split(',')
+        .filter_map(|pair| {
+            let pair = pair.trim();
+            let colon = pair.find(':')?;
+            let field = pair[..colon].trim();
+            let unit = pair[colon + 1..].trim();
+            if field.is_empty() || unit.is_empty() {
+                return None;
+            }
+            Some((field.to_string(), escape_tag_key_or_value(unit)))
+        })
+        .collect()
+}
+
+/// Parse durasi gaya Flux (`500ms`, `2s`, `5m`, `1h`) atau angka polos (ditafsir
+/// sebagai milidetik, demi kompatibilitas mundur dengan env var `*_MS` yang
+/// sudah ada sejak sebelum helper ini) menjadi jumlah milidetik. Suffix harus
+/// dicek `ms` sebelum `s` karena `"500ms"` juga berakhiran huruf `s`.
+/// Mengembalikan `None` (bukan error) kalau formatnya tidak dikenali, supaya
+/// caller config yang paling tahu default sensible untuk env var itu.
+pub fn parse_duration_ms(s: &str) -> Option<u64> {
+    let s = s.trim();
+    if s.is_empty() {
+        return None;
+    }
+    const SUFFIXES: [(&str, u64); 4] = [("ms", 1), ("s", 1_000), ("m", 60_000), ("h", 3_600_000)];
+    for (suffix, multiplier) in SUFFIXES {
+        if let Some(num) = s.strip_suffix(suffix) {
+            return num.trim().parse::<u64>().ok().map(|n| n * multiplier);
+        }
+    }
+    s.parse::<u64>().ok()
+}
+
+/// Gabungkan unit `temperature` bawaan (mengikuti `cfg.temp_unit`, sudah pasti
+/// cocok dengan field yang ditulis) dengan override dari `FIELD_UNITS`, lalu
+/// render jadi suffix tag line-protocol, misal `,temperature_unit=C,humidity_unit=%`.
+/// Representasi ini dipilih (bukan measurement `units` terpisah) karena tag
+/// menempel langsung ke titik datanya sehingga tetap benar walau unit berubah
+/// antar-baris, dan bisa langsung dipakai di `GROUP BY`/label Grafana.
+pub fn field_unit_tags_suffix(default_temperature_unit: &str, overrides: &[(String, String)]) -> String {
+    let mut tags: Vec<(String, String)> = vec![("temperature".to_string(), default_temperature_unit.to_string())];
+    for (field, unit) in overrides {
+        match tags.iter_mut().find(|(f, _)| f == field) {
+            Some(existing) => existing.1 = unit.clone(),
+            None => tags.push((field.clone(), unit.clone())),
+        }
+    }
+    tags.iter().map(|(f, u)| format!(",{}_unit={}", f, u)).collect()
+}
+
+pub fn resolve_source_tag(line: &str, default_tag_source: &str) -> String {
+    if let Some(c) = SOURCE_TAG_RE.captures(line) {
+        if let Some(m) = c.get(1).or_else(|| c.get(2)) {
+            return m.as_str().to_string();
+        }
+    }
+    default_tag_source.to_string()
+}
+
+/// Parse baris aktuasi relay firmware (`Relay1 ON (Temp 23.4)`) jadi
+/// `(nomor_relay, state)` dengan `state` 1=ON, 0=OFF. Nomor relay balik
+/// `None` kalau firmware tidak mencantumkannya (unit satu-relay), supaya
+/// caller tidak menulis tag `relay=` kosong.
+pub fn parse_relay_state(line: &str) -> Option<(Option<String>, i64)> {
+    let c = RELAY_RE.captures(line)?;
+    let relay_id = c.get(1)
+        .map(|m| m.as_str())
+        .filter(|s| !s.is_empty())
+        .map(|s| s.to_string());
+    let state = if c.get(2)?.as_str().eq_ignore_ascii_case("ON") { 1 } else { 0 };
+    Some((relay_id, state))
+}
+
+/// Parse banner boot firmware `FW:1.2.3` jadi string versinya. Dipanggil
+/// sebelum parser RH/T/relay supaya baris banner tidak nyasar dianggap baris
+/// data yang gagal parse.
+pub fn parse_fw_version(line: &str) -> Option<String> {
+    FW_VERSION_RE.captures(line).and_then(|c| c.get(1)).map(|m| m.as_str().to_string())
+}
+
+/// Render versi firmware terakhir (kalau ada) jadi suffix tag `,fw=1.2.3`
+/// yang ditempel ke titik data berikutnya -- `None` sebelum banner pertama
+/// diterima tidak menghasilkan tag sama sekali, bukan `fw=` kosong.
+pub fn fw_tag_suffix(fw_version: Option<&str>) -> String {
+    match fw_version {
+        Some(v) => format!(",fw={}", escape_tag_key_or_value(v)),
+        None => String::new(),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn line_to_influx(
+    measurement: &str,
+    default_tag_source: &str,
+    raw: &str,
+    include_raw_on_fail: bool,
+    ts: Option<i128>,
+    emit_integers: bool,
+    extra_tags: &[(String, String)],
+    allowed_fields: Option<&[String]>,
+    field_map: Option<&[(usize, String)]>,
+) -> Option<String> {
+    let fields_opt = match parse_json_fields(raw, emit_integers, allowed_fields) {
+        Ok(fields) => Some(("json", fields)),
+        Err(e_json) => {
+            debug!("parse_json_fields: {}", e_json);
+            match parse_kv_fields(raw, emit_integers, allowed_fields) {
+                Ok(fields) => Some(("kv", fields)),
+                Err(e_kv) => {
+                    debug!("parse_kv_fields: {}", e_kv);
+                    match field_map.map_or(Err(ParseError::Empty), |fm| parse_positional_csv_fields(raw, fm, emit_integers)) {
+                        Ok(fields) => Some(("csv", fields)),
+                        Err(e_csv) => {
+                            debug!("parse_positional_csv_fields: {}", e_csv);
+                            match parse_single_number(raw) {
+                                Ok(fields) => Some(("number", fields)),
+                                Err(e_num) => {
+                                    debug!("parse_single_number: {}", e_num);
+                                    None
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    };
+
+    let meas = escape_measurement(measurement);
+    let tag = escape_tag_key_or_value(&resolve_source_tag(raw, default_tag_source));
+    let extra = extra_tags_suffix(extra_tags);
+
+    if let Some((quality, fields)) = fields_opt {
+        let fields_join = fields
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect::<Vec<_>>()
+            .join(",");
+        Some(append_timestamp(format!("{},source={}{},quality={} {}", meas, tag, extra, quality, fields_join), ts))
+    } else if include_raw_on_fail {
+        let fields_join = format!("raw={}", quote_string_field(raw.trim()));
+        Some(append_timestamp(format!("{},source={}{},quality=raw {}", meas, tag, extra, fields_join), ts))
+    } else {
+        None
+    }
+}
+
+// ========================= Parser RH/T =========================
+pub fn update_pending_from_line(p: &mut Pending, line: &str, pair_timeout: Duration) -> Option<(f64, f64)> {
+    // Format firmware `RH:x,T:y` sudah membawa kedua nilai dalam satu baris,
+    // jadi bisa langsung dipasangkan tanpa menunggu partner lewat `Pending`.
+    if let Some(c) = FIRMWARE_RE.captures(line) {
+        if let (Some(rh_m), Some(t_m)) = (c.get(1), c.get(2)) {
+            if let (Ok(rh), Ok(t)) = (rh_m.as_str().parse::<f64>(), t_m.as_str().parse::<f64>()) {
+                p.rh = Some(rh);
+                p.t = Some(t);
+                return Some((rh, t));
+            }
+        }
+    }
+
+    // Buang paruh pasangan yang sudah basi sebelum menggabungkannya dengan nilai baru.
+    p.expire_stale(pair_timeout);
+
+    let mut updated = false;
+
+    if let Some(c) = RH_RE.captures(line) {
+        if let Some(m) = c.get(1) {
+            if let Ok(v) = m.as_str().parse::<f64>() {
+                p.rh = Some(v);
+                p.rh_at = Some(Instant::now());
+                updated = true;
+            }
+        }
+    }
+    if let Some(c) = T_RE.captures(line) {
+        if let Some(m) = c.get(1) {
+            if let Ok(v) = m.as_str().parse::<f64>() {
+                p.t = Some(v);
+                p.t_at = Some(Instant::now());
+                updated = true;
+            }
+        }
+    }
+    if updated {
+        if let (Some(rh), Some(t)) = (p.rh, p.t) {
+            return Some((rh, t));
+        }
+    }
+    None
+}
+
+/// Batas plausibilitas fisik: RH harus 0-100% dan suhu harus di antara
+/// `temp_min`/`temp_max` (default -40..125°C). Modbus yang lolos CRC kadang
+/// membawa nilai mustahil (mis. register kosong 0xFFFF -> RH 6553.5%); kita
+/// buang sebelum masuk Influx/ThingsBoard alih-alih meracuni dashboard.
+pub fn is_plausible_reading(rh: f64, t_c: f64, temp_min: f64, temp_max: f64) -> bool {
+    (0.0..=100.0).contains(&rh) && (temp_min..=temp_max).contains(&t_c)
+}
+
+/// Dipakai oleh mode `DEDUP=1`: titik baru dianggap duplikat hanya kalau RH
+/// dan suhu persis sama dengan titik terakhir yang ditulis DAN belum
+/// melewati `max_gap_ms` sejak itu — gap dipaksa supaya seri tidak pernah
+/// diam total lebih dari `DEDUP_MAX_GAP_MS` walau nilainya tidak berubah.
+pub fn is_duplicate_reading(
+    last_written: Option<(f64, f64, Instant)>,
+    rh: f64,
+    t: f64,
+    now: Instant,
+    max_gap_ms: u64,
+) -> bool {
+    match last_written {
+        Some((last_rh, last_t, last_at)) => {
+            last_rh == rh && last_t == t && now.duration_since(last_at) < Duration::from_millis(max_gap_ms)
+        }
+        None => false,
+    }
+}
+
+/// Dipakai oleh `TB_PUBLISH_DELTA`: publish ke ThingsBoard hanya kalau suhu
+/// ATAU RH berubah minimal `delta` dari titik terakhir yang dipublish, KECUALI
+/// `TB_MAX_INTERVAL_MS` sudah lewat -- supaya dashboard TB tidak kosong lama
+/// saat nilai benar-benar diam (mis. ruangan ber-AC yang stabil), tapi juga
+/// tidak membanjiri broker MQTT dengan titik yang hampir identik.
+pub fn should_publish_to_tb(
+    last_published: Option<(f64, f64, Instant)>,
+    temp: f64,
+    hum: f64,
+    now: Instant,
+    delta: Option<f64>,
+    max_interval_ms: u64,
+) -> bool {
+    let (last_temp, last_hum, last_at) = match last_published {
+        Some(v) => v,
+        None => return true,
+    };
+    if now.duration_since(last_at) >= Duration::from_millis(max_interval_ms) {
+        return true;
+    }
+    match delta {
+        Some(d) => (temp - last_temp).abs() >= d || (hum - last_hum).abs() >= d,
+        None => true,
+    }
+}
+
+// ========================= Util suhu =========================
+pub fn celsius_to_fahrenheit(c: f64) -> f64 {
+    c * 9.0 / 5.0 + 32.0
+}
+
+/// Pembulatan opsional sebelum nilai ditulis keluar (line protocol, JSON TB).
+/// `None` berarti ROUND_DECIMALS tidak diset -- nilai mentah hasil konversi/EMA
+/// lewat apa adanya, termasuk sisa floating-point seperti `26.100000000000001`.
+pub fn round_decimals(value: f64, decimals: Option<u32>) -> f64 {
+    match decimals {
+        Some(d) => {
+            let factor = 10f64.powi(d as i32);
+            (value * factor).round() / factor
+        }
+        None => value,
+    }
+}
+
+/// Titik embun via perkiraan Magnus (konstanta Alduchov & Eskridge 1996),
+/// akurat untuk rentang suhu khas sensor lingkungan (0-60°C).
+pub fn dew_point(t_c: f64, rh: f64) -> f64 {
+    const A: f64 = 17.625;
+    const B: f64 = 243.04;
+    let gamma = (rh / 100.0).ln() + (A * t_c) / (B + t_c);
+    (B * gamma) / (A - gamma)
+}
+
+// ========================= Query Influx: parsing hasil =========================
+// `temperature`/`humidity` dibuat `Option` karena measurement lama atau bucket
+// yang ditulis writer lain kadang cuma punya salah satu field -- dulu ini
+// selalu dianggap error keras, padahal publish TB parsial (cuma yang ada)
+// tetap lebih berguna daripada tidak publish sama sekali.
+#[derive(Debug)]
+pub struct Latest {
+    pub temperature: Option<f64>,
+    pub humidity: Option<f64>,
+    pub ts_ms: i64,
+}
+
+/// Parsing CSV hasil query Flux dipisah dari `query_latest_influx` supaya bisa
+/// diuji tanpa server InfluxDB sungguhan -- cukup suapi teks CSV yang sama
+/// bentuknya dengan yang dikembalikan `/api/v2/query`.
+pub fn parse_latest_from_flux_csv(text: &str, field_temperature: &str, field_humidity: &str) -> Result<Latest> {
+    let mut rdr = csv::ReaderBuilder::new()
+        .has_headers(true)
+        .comment(Some(b'#'))
+        .from_reader(text.as_bytes());
+
+    let headers = rdr.headers()?.clone();
+    let i_time = headers.iter().position(|h| h == "_time")
+        .ok_or_else(|| anyhow!("Kolom _time tidak ada"))?;
+    // Tidak pakai `ok_or_else` di sini: kolom temperature/humidity boleh tidak
+    // ada sama sekali kalau measurement itu cuma pernah ditulis salah satunya.
+    let i_temp = headers.iter().position(|h| h == field_temperature);
+    let i_hum = headers.iter().position(|h| h == field_humidity);
+    if i_temp.is_none() && i_hum.is_none() {
+        return Err(anyhow!("Kolom {} maupun {} tidak ada", field_temperature, field_humidity));
+    }
+
+    for rec in rdr.records() {
+        let rec = rec?;
+        let t_str = rec.get(i_time).unwrap_or("");
+        if t_str.is_empty() {
+            continue;
+        }
+        let temp = i_temp.and_then(|i| rec.get(i)).filter(|s| !s.is_empty()).and_then(|s| s.parse::<f64>().ok());
+        let hum = i_hum.and_then(|i| rec.get(i)).filter(|s| !s.is_empty()).and_then(|s| s.parse::<f64>().ok());
+        if temp.is_none() && hum.is_none() {
+            continue;
+        }
+        let t_parsed: DateTime<Utc> = t_str.parse().context("Parse _time RFC3339 gagal")?;
+        return Ok(Latest { temperature: temp, humidity: hum, ts_ms: t_parsed.timestamp_millis() });
+    }
+
+    Err(anyhow!("Tidak ada baris data pada hasil query Influx"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_duration_ms_accepts_each_suffix() {
+        assert_eq!(parse_duration_ms("500ms"), Some(500));
+        assert_eq!(parse_duration_ms("2s"), Some(2_000));
+        assert_eq!(parse_duration_ms("5m"), Some(300_000));
+        assert_eq!(parse_duration_ms("1h"), Some(3_600_000));
+    }
+
+    #[test]
+    fn parse_duration_ms_bare_number_is_milliseconds() {
+        // Kompatibilitas mundur dengan env var `*_MS` lama yang cuma angka polos.
+        assert_eq!(parse_duration_ms("1500"), Some(1_500));
+    }
+
+    #[test]
+    fn parse_duration_ms_rejects_garbage_and_empty() {
+        assert_eq!(parse_duration_ms("banana"), None);
+        assert_eq!(parse_duration_ms(""), None);
+        assert_eq!(parse_duration_ms("   "), None);
+    }
+
+    #[test]
+    fn parses_compact_firmware_format() {
+        let mut pending = Pending::new();
+        let result = update_pending_from_line(&mut pending, "RH:55.0,T:27.3", Duration::from_secs(5));
+        assert_eq!(result, Some((55.0, 27.3)));
+    }
+
+    #[test]
+    fn fw_version_banner_is_parsed() {
+        assert_eq!(parse_fw_version("FW:1.2.3"), Some("1.2.3".to_string()));
+        assert_eq!(parse_fw_version("RH:55.0,T:27.3"), None);
+    }
+
+    #[test]
+    fn fw_tag_suffix_empty_before_first_banner() {
+        assert_eq!(fw_tag_suffix(None), "");
+        assert_eq!(fw_tag_suffix(Some("1.2.3")), ",fw=1.2.3");
+    }
+
+    #[test]
+    fn relay_on_message_parses_with_relay_number_and_state_one() {
+        let result = parse_relay_state("Relay1 ON (Temp 23.4)");
+        assert_eq!(result, Some((Some("1".to_string()), 1)));
+    }
+
+    #[test]
+    fn relay_off_message_parses_with_relay_number_and_state_zero() {
+        let result = parse_relay_state("Relay2 OFF (Temp 18.9)");
+        assert_eq!(result, Some((Some("2".to_string()), 0)));
+    }
+
+    #[test]
+    fn json_fields_flattens_one_level_nested_object() {
+        let mut fields = parse_json_fields(r#"{"t":26.1,"rh":55,"meta":{"fw":"1.2"}}"#, false, None).unwrap();
+        fields.sort();
+        assert_eq!(fields, vec![
+            ("meta.fw".to_string(), "\"1.2\"".to_string()),
+            ("rh".to_string(), "55".to_string()),
+            ("t".to_string(), "26.1".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn kv_fields_keep_quoted_value_with_spaces_intact() {
+        let fields = parse_kv_fields(r#"a=1 status="all good" b=2"#, false, None).unwrap();
+        assert_eq!(fields.len(), 3);
+        assert_eq!(fields[0], ("a".to_string(), "1".to_string()));
+        assert_eq!(fields[1], ("status".to_string(), "\"all good\"".to_string()));
+        assert_eq!(fields[2], ("b".to_string(), "2".to_string()));
+    }
+
+    #[test]
+    fn single_number_handles_negative_scientific_and_leading_plus() {
+        let fields = parse_single_number("-5").unwrap();
+        assert_eq!(fields, vec![("value".to_string(), "-5".to_string())]);
+
+        let fields = parse_single_number("3.2e1").unwrap();
+        assert_eq!(fields, vec![("value".to_string(), "32".to_string())]);
+
+        let fields = parse_single_number("+23.5").unwrap();
+        assert_eq!(fields, vec![("value".to_string(), "23.5".to_string())]);
+
+        let fields = parse_single_number("-0.0").unwrap();
+        assert_eq!(fields, vec![("value".to_string(), "-0".to_string())]);
+    }
+
+    #[test]
+    fn kv_fields_rejects_token_with_multiple_equals() {
+        let err = parse_kv_fields("weird=a=b", false, None).unwrap_err();
+        assert!(matches!(err, ParseError::NoFields));
+
+        // Token lain di baris yang sama tetap diproses normal.
+        let fields = parse_kv_fields("weird=a=b,ok=1", false, None).unwrap();
+        assert_eq!(fields, vec![("ok".to_string(), "1".to_string())]);
+    }
+
+    #[test]
+    fn kv_fields_emit_integer_suffix_only_when_enabled() {
+        let fields = parse_kv_fields("count=42", false, None).unwrap();
+        assert_eq!(fields[0], ("count".to_string(), "42".to_string()));
+
+        let fields = parse_kv_fields("count=42", true, None).unwrap();
+        assert_eq!(fields[0], ("count".to_string(), "42i".to_string()));
+
+        // Nilai non-bulat tetap float meski EMIT_INTEGERS aktif.
+        let fields = parse_kv_fields("temperature=26.1", true, None).unwrap();
+        assert_eq!(fields[0], ("temperature".to_string(), "26.1".to_string()));
+    }
+
+    #[test]
+    fn allowed_fields_drops_keys_outside_the_allowlist() {
+        let allowed = vec!["temperature".to_string(), "humidity".to_string()];
+        let fields = parse_kv_fields("temperature=26.1,humidity=55,debug=1", false, Some(&allowed)).unwrap();
+        assert_eq!(fields, vec![
+            ("temperature".to_string(), "26.1".to_string()),
+            ("humidity".to_string(), "55".to_string()),
+        ]);
+
+        // `serde_json::Map` tanpa fitur `preserve_order` mengurutkan key secara
+        // alfabetis, jadi "debug" yang terbuang tidak mengubah urutan dua
+        // field yang tersisa (humidity sebelum temperature).
+        let mut fields = parse_json_fields(r#"{"temperature":26.1,"humidity":55,"debug":1}"#, false, Some(&allowed)).unwrap();
+        fields.sort();
+        assert_eq!(fields, vec![
+            ("humidity".to_string(), "55".to_string()),
+            ("temperature".to_string(), "26.1".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn field_map_parses_index_to_name_pairs() {
+        let map = parse_field_map("0:temperature,1:humidity,2:pressure");
+        assert_eq!(map, vec![
+            (0, "temperature".to_string()),
+            (1, "humidity".to_string()),
+            (2, "pressure".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn positional_csv_maps_three_values_to_named_fields() {
+        let map = parse_field_map("0:temperature,1:humidity,2:pressure");
+        let fields = parse_positional_csv_fields("26.1,55.0,101.3", &map, false).unwrap();
+        assert_eq!(fields, vec![
+            ("temperature".to_string(), "26.1".to_string()),
+            ("humidity".to_string(), "55".to_string()),
+            ("pressure".to_string(), "101.3".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn positional_csv_drops_indexes_outside_the_map() {
+        // Hanya indeks 0 dan 1 dipetakan -- token ketiga dibuang, bukan error.
+        let map = parse_field_map("0:temperature,1:humidity");
+        let fields = parse_positional_csv_fields("26.1,55.0,101.3", &map, false).unwrap();
+        assert_eq!(fields, vec![
+            ("temperature".to_string(), "26.1".to_string()),
+            ("humidity".to_string(), "55".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn line_to_influx_tags_quality_csv_for_positional_mapping() {
+        let no_tags: Vec<(String, String)> = Vec::new();
+        let map = parse_field_map("0:temperature,1:humidity,2:pressure");
+        let lp = line_to_influx("sensors", "default", "26.1,55.0,101.3", false, None, false, &no_tags, None, Some(&map)).unwrap();
+        assert!(lp.contains(",quality=csv "), "expected quality=csv tag in: {}", lp);
+        assert!(lp.contains("temperature=26.1"), "{}", lp);
+        assert!(lp.contains("humidity=55"), "{}", lp);
+        assert!(lp.contains("pressure=101.3"), "{}", lp);
+    }
+
+    #[test]
+    fn source_tag_prefers_sid_over_default() {
+        assert_eq!(resolve_source_tag("SID:2,RH:55.0,T:27.3", "COM15"), "2");
+        assert_eq!(resolve_source_tag("source=chamberA value=1", "COM15"), "chamberA");
+    }
+
+    #[test]
+    fn source_tag_falls_back_to_default() {
+        assert_eq!(resolve_source_tag("RH:55.0,T:27.3", "COM15"), "COM15");
+    }
+
+    #[test]
+    fn stale_half_pair_is_not_combined_with_fresh_one() {
+        let mut pending = Pending::new();
+        pending.rh = Some(55.0);
+        pending.rh_at = Some(Instant::now() - Duration::from_secs(10));
+
+        let timeout = Duration::from_secs(2);
+        let result = update_pending_from_line(&mut pending, "T = 26.0 C", timeout);
+        // RH lama (10s) harus sudah dibuang sebelum T baru tiba, jadi belum ada pasangan.
+        assert_eq!(result, None);
+        assert_eq!(pending.rh, None);
+        assert_eq!(pending.t, Some(26.0));
+    }
+
+    #[test]
+    fn celsius_to_fahrenheit_known_points() {
+        assert_eq!(celsius_to_fahrenheit(0.0), 32.0);
+        assert_eq!(celsius_to_fahrenheit(100.0), 212.0);
+    }
+
+    #[test]
+    fn round_decimals_trims_floating_point_noise() {
+        // `celsius_to_fahrenheit(-13.0)` sendiri memunculkan sisa floating-point
+        // (8.600000000000001...), bukan literal yang ditulis tangan.
+        let noisy = celsius_to_fahrenheit(-13.0);
+        assert_ne!(noisy, 8.6);
+        assert_eq!(round_decimals(noisy, Some(1)), 8.6);
+        assert_eq!(round_decimals(26.789, Some(2)), 26.79);
+    }
+
+    #[test]
+    fn round_decimals_passes_through_when_disabled() {
+        let noisy = celsius_to_fahrenheit(-13.0);
+        assert_eq!(round_decimals(noisy, None), noisy);
+    }
+
+    #[test]
+    fn append_timestamp_only_when_present() {
+        assert_eq!(append_timestamp("m f=1".into(), Some(42)), "m f=1 42");
+        assert_eq!(append_timestamp("m f=1".into(), None), "m f=1");
+    }
+
+    #[test]
+    fn duplicate_reading_detected_within_gap() {
+        let last = Some((55.0, 26.1, Instant::now()));
+        assert!(is_duplicate_reading(last, 55.0, 26.1, Instant::now(), 5 * 60 * 1000));
+        assert!(!is_duplicate_reading(last, 55.0, 26.2, Instant::now(), 5 * 60 * 1000));
+    }
+
+    #[test]
+    fn duplicate_reading_forced_after_max_gap() {
+        let last = Some((55.0, 26.1, Instant::now() - Duration::from_millis(200)));
+        assert!(!is_duplicate_reading(last, 55.0, 26.1, Instant::now(), 100));
+    }
+
+    #[test]
+    fn dew_point_matches_known_reference() {
+        // 25°C / 50%RH -> sekitar 13.9°C (tabel psikrometrik standar).
+        let dp = dew_point(25.0, 50.0);
+        assert!((dp - 13.9).abs() < 0.1, "dew_point(25, 50) = {}", dp);
+    }
+
+    #[test]
+    fn plausible_reading_accepts_boundary_values() {
+        assert!(is_plausible_reading(0.0, -40.0, -40.0, 125.0));
+        assert!(is_plausible_reading(100.0, 125.0, -40.0, 125.0));
+    }
+
+    #[test]
+    fn plausible_reading_rejects_out_of_range_values() {
+        // RH 6553.5% adalah hasil khas register kosong (0xFFFF / 10.0) yang lolos CRC.
+        assert!(!is_plausible_reading(6553.5, 25.0, -40.0, 125.0));
+        assert!(!is_plausible_reading(-0.1, 25.0, -40.0, 125.0));
+        assert!(!is_plausible_reading(50.0, -40.1, -40.0, 125.0));
+        assert!(!is_plausible_reading(50.0, 125.1, -40.0, 125.0));
+    }
+
+    #[test]
+    fn extra_tags_are_appended_and_escaped() {
+        // Spasi pada value ter-escape lewat parse_extra_tags (koma dipakai sebagai
+        // pemisah pasangan, jadi koma di dalam value diuji langsung lewat
+        // extra_tags_suffix, tanpa lewat parser).
+        let tags = parse_extra_tags("site=plant A,line=3");
+        let lp = line_to_influx("sensors", "default", "count=1", false, None, false, &tags, None, None).unwrap();
+        assert_eq!(lp, r"sensors,source=default,site=plant\ A,line=3,quality=kv count=1");
+
+        let tags_with_comma = vec![("note".to_string(), escape_tag_key_or_value("a,b"))];
+        assert_eq!(extra_tags_suffix(&tags_with_comma), r",note=a\,b");
+    }
+
+    #[test]
+    fn line_to_influx_tags_quality_by_which_parser_matched() {
+        let no_tags: Vec<(String, String)> = Vec::new();
+        let json = line_to_influx("sensors", "default", r#"{"x":1}"#, false, None, false, &no_tags, None, None).unwrap();
+        assert!(json.contains(",quality=json "), "expected quality=json in {}", json);
+
+        let number = line_to_influx("sensors", "default", "42", false, None, false, &no_tags, None, None).unwrap();
+        assert!(number.contains(",quality=number "), "expected quality=number in {}", number);
+
+        let raw = line_to_influx("sensors", "default", "garbage not parseable", true, None, false, &no_tags, None, None).unwrap();
+        assert!(raw.contains(",quality=raw "), "expected quality=raw in {}", raw);
+    }
+
+    #[test]
+    fn field_units_adds_extra_fields_without_disturbing_temperature_default() {
+        let overrides = parse_field_units("humidity:%,dewpoint:C");
+        assert_eq!(
+            field_unit_tags_suffix("C", &overrides),
+            ",temperature_unit=C,humidity_unit=%,dewpoint_unit=C"
+        );
+    }
+
+    #[test]
+    fn field_units_override_replaces_temperature_default() {
+        let overrides = parse_field_units("temperature:F");
+        assert_eq!(field_unit_tags_suffix("C", &overrides), ",temperature_unit=F");
+    }
+
+    #[test]
+    fn latest_from_flux_csv_tolerates_missing_humidity_column() {
+        let csv = "_time,temperature\n2026-01-01T00:00:00Z,26.5\n";
+        let latest = parse_latest_from_flux_csv(csv, "temperature", "humidity").unwrap();
+        assert_eq!(latest.temperature, Some(26.5));
+        assert_eq!(latest.humidity, None);
+    }
+
+    #[test]
+    fn should_publish_to_tb_respects_delta_and_max_interval() {
+        let now = Instant::now();
+        // Belum pernah publish sebelumnya -> selalu publish.
+        assert!(should_publish_to_tb(None, 25.0, 50.0, now, Some(0.5), 60_000));
+
+        // Perubahan di bawah delta dan belum lewat max interval -> skip.
+        let last = Some((25.0, 50.0, now));
+        assert!(!should_publish_to_tb(last, 25.2, 50.1, now, Some(0.5), 60_000));
+
+        // Perubahan di atas delta -> publish.
+        assert!(should_publish_to_tb(last, 26.0, 50.1, now, Some(0.5), 60_000));
+
+        // Tanpa delta (None) -> selalu publish.
+        assert!(should_publish_to_tb(last, 25.0, 50.0, now, None, 60_000));
+
+        // Sudah lewat max interval walau nilainya identik -> tetap publish.
+        let stale = Some((25.0, 50.0, now - Duration::from_millis(61_000)));
+        assert!(should_publish_to_tb(stale, 25.0, 50.0, now, Some(0.5), 60_000));
+    }
+
+    #[test]
+    fn latest_from_flux_csv_errors_when_both_fields_missing() {
+        let csv = "_time,unrelated\n2026-01-01T00:00:00Z,1\n";
+        assert!(parse_latest_from_flux_csv(csv, "temperature", "humidity").is_err());
+    }
+}
@@ -1,133 +1,94 @@
 use anyhow::{anyhow, Context, Result};
 use dotenvy::dotenv;
+use log::{debug, error, info, warn};
 use reqwest::blocking::Client;
 use serde_json::Value;
 use std::env;
-use std::io::{BufRead, BufReader};
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
-use regex::Regex;
-use once_cell::sync::Lazy;
+use std::io::{BufRead, BufReader, Write};
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use chrono::{DateTime, Utc};
 use csv;
-use rumqttc::{MqttOptions, Client as MqttClient, QoS};
+use flate2::{write::GzEncoder, Compression};
+use regex::Regex;
+use rumqttc::{LastWill, MqttOptions, Client as MqttClient, QoS};
+
+use serial_to_influx::{
+    append_timestamp, celsius_to_fahrenheit, dew_point, escape_measurement,
+    escape_tag_key_or_value, extra_tags_suffix, field_unit_tags_suffix, fw_tag_suffix,
+    is_duplicate_reading, is_plausible_reading, line_to_influx, parse_duration_ms, parse_extra_tags,
+    parse_field_map, parse_field_units, parse_fw_version, parse_latest_from_flux_csv, parse_relay_state,
+    resolve_source_tag, round_decimals, should_publish_to_tb, update_pending_from_line, Latest,
+    Pending,
+};
+
+// ========================= Waktu & helper LP =========================
+// Timestamp terakhir yang dikembalikan `now_nanos`, dipakai `monotonic_nanos`
+// supaya lompatan jam mundur (koreksi NTP) tidak menghasilkan titik
+// out-of-order -- Influx memperlakukan timestamp yang sama/terbalik sebagai
+// overwrite, yang diam-diam menghilangkan data.
+static LAST_EMITTED_NANOS: AtomicI64 = AtomicI64::new(0);
 
-// ========================= Regex input serial =========================
-static RH_RE: Lazy<Regex> = Lazy::new(|| {
-    Regex::new(r"(?i)\bRH\b\s*=\s*([0-9]+(?:\.[0-9]+)?)\s*%").unwrap()
-});
-static T_RE: Lazy<Regex> = Lazy::new(|| {
-    Regex::new(r"(?i)\bT\b\s*=\s*([0-9]+(?:\.[0-9]+)?)\s*°?\s*C").unwrap()
-});
+// Indeks endpoint INFLUX_URL yang terakhir kali berhasil dipakai -- dicoba
+// lebih dulu pada write berikutnya supaya sekali failover ke standby, kita
+// tidak bolak-balik memukul primary yang sedang down di tiap baris.
+static LAST_WORKING_ENDPOINT: AtomicUsize = AtomicUsize::new(0);
 
-struct Pending {
-    rh: Option<f64>,
-    t: Option<f64>,
+/// Jamin `raw` yang dikembalikan tidak pernah mundur/sama dibanding `last`:
+/// kalau jam mundur (atau dua panggilan terjadi di detik yang sama sampai
+/// resolusi nanodetik bertabrakan), nilai sebelumnya dinaikkan 1ns alih-alih
+/// dipakai ulang. CAS-loop karena beberapa thread bisa memanggil `now_nanos`
+/// bersamaan (mis. thread metrics server & loop utama).
+fn monotonic_nanos(last: &AtomicI64, raw: i64) -> i64 {
+    let mut prev = last.load(Ordering::SeqCst);
+    loop {
+        let next = if raw > prev { raw } else { prev.saturating_add(1) };
+        match last.compare_exchange(prev, next, Ordering::SeqCst, Ordering::SeqCst) {
+            Ok(_) => return next,
+            Err(actual) => prev = actual,
+        }
+    }
 }
 
-// ========================= Waktu & helper LP =========================
 fn now_nanos() -> i128 {
     let now = SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .unwrap_or(Duration::from_secs(0));
-    (now.as_secs() as i128) * 1_000_000_000i128 + (now.subsec_nanos() as i128)
-}
-
-fn escape_measurement(s: &str) -> String {
-    s.replace(',', r"\,").replace(' ', r"\ ").replace('=', r"\=")
-}
-fn escape_tag_key_or_value(s: &str) -> String {
-    s.replace(',', r"\,").replace(' ', r"\ ").replace('=', r"\=")
-}
-fn escape_field_key(s: &str) -> String {
-    s.replace(',', r"\,").replace(' ', r"\ ").replace('=', r"\=")
-}
-fn quote_string_field(s: &str) -> String {
-    let escaped = s.replace('\\', r"\\").replace('"', r#"\""#);
-    format!("\"{}\"", escaped)
-}
-
-fn parse_json_fields(s: &str) -> Option<Vec<(String, String)>> {
-    let v: Value = serde_json::from_str(s).ok()?;
-    let obj = v.as_object()?;
-    let mut fields = Vec::new();
-    for (k, val) in obj {
-        if let Some(n) = val.as_f64() {
-            fields.push((escape_field_key(k), format!("{}", n)));
-        } else if let Some(b) = val.as_bool() {
-            fields.push((escape_field_key(k), format!("{}", b)));
-        } else if let Some(st) = val.as_str() {
-            fields.push((escape_field_key(k), quote_string_field(st)));
-        }
-    }
-    if fields.is_empty() { None } else { Some(fields) }
-}
-
-fn parse_kv_fields(s: &str) -> Option<Vec<(String, String)>> {
-    let sep: &[_] = &[',', ' '];
-    let parts: Vec<&str> = s.split(sep).filter(|t| !t.is_empty()).collect();
-    if parts.is_empty() { return None; }
-    let mut got = Vec::new();
-    for p in parts {
-        if let Some(eq) = p.find('=') {
-            let key = &p[..eq];
-            let val = &p[eq + 1..];
-            if key.is_empty() { continue; }
-            let key_esc = escape_field_key(key.trim());
-            if let Ok(n) = val.trim().parse::<f64>() {
-                got.push((key_esc, format!("{}", n)));
-            } else if val.eq_ignore_ascii_case("true") || val.eq_ignore_ascii_case("false") {
-                got.push((key_esc, val.to_ascii_lowercase()));
-            } else {
-                got.push((key_esc, quote_string_field(val.trim())));
-            }
-        }
-    }
-    if got.is_empty() { None } else { Some(got) }
+    let raw = (now.as_secs() as i64) * 1_000_000_000i64 + (now.subsec_nanos() as i64);
+    monotonic_nanos(&LAST_EMITTED_NANOS, raw) as i128
 }
 
-fn parse_single_number(s: &str) -> Option<Vec<(String, String)>> {
-    let trimmed = s.trim();
-    if trimmed.is_empty() { return None; }
-    if let Ok(n) = trimmed.parse::<f64>() {
-        Some(vec![(escape_field_key("value"), format!("{}", n))])
-    } else { None }
+/// Menyaring timestamp mentah sesuai `INFLUX_TIMESTAMP_SOURCE`: `none` selalu
+/// membiarkan Influx memberi waktu server, `host` (default) memakai jam host
+/// tapi menolak nilai <= 0 (jam sebelum epoch/rusak) alih-alih menulis
+/// timestamp yang salah secara senyap. Dipisah dari `now_nanos` supaya
+/// logikanya bisa diuji tanpa memalsukan jam sistem.
+fn guard_timestamp(raw_ts: i128, source: TimestampSource) -> Option<i128> {
+    if source == TimestampSource::None {
+        return None;
+    }
+    if raw_ts <= 0 {
+        warn!("Jam host menghasilkan timestamp <= 0 ({}), menulis tanpa timestamp eksplisit", raw_ts);
+        return None;
+    }
+    Some(raw_ts)
 }
 
-fn line_to_influx(
-    measurement: &str,
-    default_tag_source: &str,
-    raw: &str,
-    include_raw_on_fail: bool,
-) -> Option<String> {
-    let fields_opt = parse_json_fields(raw)
-        .or_else(|| parse_kv_fields(raw))
-        .or_else(|| parse_single_number(raw));
-
-    let ts = now_nanos();
-    let meas = escape_measurement(measurement);
-    let tag = escape_tag_key_or_value(default_tag_source);
-
-    if let Some(fields) = fields_opt {
-        let fields_join = fields
-            .iter()
-            .map(|(k, v)| format!("{}={}", k, v))
-            .collect::<Vec<_>>()
-            .join(",");
-        Some(format!("{},source={} {} {}", meas, tag, fields_join, ts))
-    } else if include_raw_on_fail {
-        let fields_join = format!("raw={}", quote_string_field(raw.trim()));
-        Some(format!("{},source={} {} {}", meas, tag, fields_join, ts))
-    } else {
-        None
-    }
+fn timestamp_for_line(source: TimestampSource, precision: InfluxPrecision) -> Option<i128> {
+    guard_timestamp(now_nanos(), source).map(|ts| ts / precision.divisor())
 }
 
 // ========================= Konfigurasi =========================
 struct Config {
-    influx_url: String,
+    // Selalu minimal satu elemen (divalidasi di `from_env`); elemen pertama
+    // adalah primary yang dipakai health-check/query (read path tidak perlu
+    // failover, hanya write path lewat `post_line`).
+    influx_urls: Vec<String>,
     influx_token: String,
     influx_org: String,
+    influx_org_id: Option<String>,
     influx_bucket: String,
     measurement: String,
     tag_source: String,
@@ -135,36 +96,792 @@ struct Config {
     baudrate: u32,
     include_raw_on_fail: bool,
 
+    tb_enabled: bool,
+    mqtt_topic: String,
+    mqtt_lwt_topic: String,
+    mqtt_lwt_payload: String,
     tb_host: String,
     tb_port: u16,
     tb_token: String,
     tb_client_id: String,
     tb_use_tls: bool,
+
+    retry: RetryConfig,
+    batch_size: usize,
+    batch_interval: Duration,
+    spool_path: Option<String>,
+    influx_version: InfluxVersion,
+    influx_user: Option<String>,
+    influx_password: Option<String>,
+    pair_timeout: Duration,
+    temp_unit: TempUnit,
+    influx_bucket_raw: Option<String>,
+    metrics_port: Option<u16>,
+    query_range: String,
+    field_temperature: String,
+    field_humidity: String,
+    input_mode: InputMode,
+    input_tcp_addr: Option<String>,
+    input_file: Option<String>,
+    dry_run: bool,
+    temp_min: f64,
+    temp_max: f64,
+    timestamp_source: TimestampSource,
+    influx_precision: InfluxPrecision,
+    emit_integers: bool,
+    dedup: bool,
+    dedup_max_gap_ms: u64,
+    smooth_alpha: Option<f64>,
+    smooth_apply_to: SmoothTarget,
+    influx_ca_cert: Option<String>,
+    influx_insecure: bool,
+    tb_qos: QoS,
+    tb_retain: bool,
+    tb_include_ts: bool,
+    tb_source: TbSource,
+    tb_query_fallback_to_direct: bool,
+    extra_tags: Vec<(String, String)>,
+    heartbeat_interval_ms: Option<u64>,
+    output_json: bool,
+    csv_path: Option<String>,
+    measurement_raw: String,
+    tb_publish_settle_ms: u64,
+    tb_publish_delta: Option<f64>,
+    tb_max_interval_ms: u64,
+    field_units: Vec<(String, String)>,
+    influx_gzip: bool,
+    tb_include_raw: bool,
+    cb_failure_threshold: u32,
+    cb_cooldown: Duration,
+    round_decimals: Option<u32>,
+    startup_discard: Duration,
+    banner_regex: Option<Regex>,
+    agg_window: Duration,
+    allowed_fields: Option<Vec<String>>,
+    field_map: Vec<(usize, String)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TempUnit {
+    Celsius,
+    Fahrenheit,
+}
+
+/// Kemana hasil smoothing `SMOOTH_ALPHA` dipakai. Default `Tb`: Influx tetap
+/// menyimpan nilai mentah (berguna untuk audit/debug sensor), ThingsBoard
+/// yang melihat kurva yang sudah dihaluskan.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SmoothTarget {
+    Tb,
+    Influx,
+    Both,
+}
+
+/// Sumber nilai yang dipublish ke ThingsBoard. `Influx` (default) query balik
+/// titik yang baru ditulis — dibutuhkan kalau ada writer lain yang ikut
+/// menulis ke bucket yang sama dan kita ingin TB melihat hasil agregatnya.
+/// `Direct` melewati round-trip itu dan memakai nilai yang baru diparse.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TbSource {
+    Influx,
+    Direct,
+}
+
+/// Exponential moving average satu variabel. `alpha` mendekati 1 artinya
+/// hampir tidak ada smoothing (langsung ikut sampel baru), mendekati 0
+/// artinya sangat lambat merespons perubahan. Sampel pertama selalu jadi
+/// nilai awal tanpa pencampuran karena belum ada riwayat.
+struct Ema {
+    alpha: f64,
+    value: Option<f64>,
+}
+
+impl Ema {
+    fn new(alpha: f64) -> Self {
+        Self { alpha, value: None }
+    }
+
+    fn update(&mut self, sample: f64) -> f64 {
+        let next = match self.value {
+            Some(prev) => self.alpha * sample + (1.0 - self.alpha) * prev,
+            None => sample,
+        };
+        self.value = Some(next);
+        next
+    }
+}
+
+/// Hasil satu window `WindowAggregator` yang sudah ditutup: rata-rata dan
+/// deviasi standar populasi (bukan sampel -- window sudah berisi seluruh
+/// data window itu, bukan subset darinya) untuk RH dan T.
+struct AggregatedWindow {
+    count: u64,
+    mean_rh: f64,
+    stddev_rh: f64,
+    mean_t: f64,
+    stddev_t: f64,
+}
+
+/// Akumulator agregasi RH/T per window waktu (`AGG_WINDOW_MS`). Dipakai
+/// ketika firmware dipindah ke polling cepat supaya Influx tidak kebanjiran
+/// satu titik per sampel -- `push` hanya menutup window dan mengembalikan
+/// hasilnya begitu sampel berikutnya datang setelah window berjalan penuh,
+/// bukan lewat timer terpisah, supaya tidak perlu thread/async tambahan.
+struct WindowAggregator {
+    window: Duration,
+    window_start: Option<Instant>,
+    count: u64,
+    sum_rh: f64,
+    sum_sq_rh: f64,
+    sum_t: f64,
+    sum_sq_t: f64,
+}
+
+impl WindowAggregator {
+    fn new(window: Duration) -> Self {
+        Self {
+            window,
+            window_start: None,
+            count: 0,
+            sum_rh: 0.0,
+            sum_sq_rh: 0.0,
+            sum_t: 0.0,
+            sum_sq_t: 0.0,
+        }
+    }
+
+    fn accumulate(&mut self, rh: f64, t: f64) {
+        self.count += 1;
+        self.sum_rh += rh;
+        self.sum_sq_rh += rh * rh;
+        self.sum_t += t;
+        self.sum_sq_t += t * t;
+    }
+
+    /// Menutup window yang sedang berjalan dan mengembalikan statistiknya,
+    /// atau `None` kalau belum ada sampel sama sekali (window kosong tidak
+    /// perlu menulis titik kosong ke Influx).
+    fn flush(&mut self) -> Option<AggregatedWindow> {
+        if self.count == 0 {
+            self.window_start = None;
+            return None;
+        }
+        let n = self.count as f64;
+        let mean_rh = self.sum_rh / n;
+        let mean_t = self.sum_t / n;
+        let variance_rh = (self.sum_sq_rh / n - mean_rh * mean_rh).max(0.0);
+        let variance_t = (self.sum_sq_t / n - mean_t * mean_t).max(0.0);
+        let result = AggregatedWindow {
+            count: self.count,
+            mean_rh,
+            stddev_rh: variance_rh.sqrt(),
+            mean_t,
+            stddev_t: variance_t.sqrt(),
+        };
+        self.count = 0;
+        self.sum_rh = 0.0;
+        self.sum_sq_rh = 0.0;
+        self.sum_t = 0.0;
+        self.sum_sq_t = 0.0;
+        self.window_start = None;
+        Some(result)
+    }
+
+    /// Menambahkan satu sampel. Kalau ini sampel pertama window baru, window
+    /// langsung mulai dan tidak ada apa pun yang di-flush. Kalau window
+    /// sebelumnya sudah lewat `self.window`, window lama ditutup dulu
+    /// (dikembalikan) sebelum sampel ini jadi sampel pertama window baru.
+    fn push(&mut self, rh: f64, t: f64, now: Instant) -> Option<AggregatedWindow> {
+        match self.window_start {
+            None => {
+                self.window_start = Some(now);
+                self.accumulate(rh, t);
+                None
+            }
+            Some(start) if now.duration_since(start) >= self.window => {
+                let closed = self.flush();
+                self.window_start = Some(now);
+                self.accumulate(rh, t);
+                closed
+            }
+            Some(_) => {
+                self.accumulate(rh, t);
+                None
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum InfluxVersion {
+    V1,
+    V2,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum InputMode {
+    Serial,
+    Tcp,
+    File,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TimestampSource {
+    Host,
+    None,
+}
+
+/// Presisi timestamp yang dikirim ke InfluxDB. Default `Ns` sama dengan
+/// perilaku lama (line protocol selalu ditulis nanodetik); bucket yang
+/// retention policy-nya downsample ke detik kadang minta presisi lebih
+/// kasar supaya tidak membuang byte untuk resolusi yang tidak pernah dipakai.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum InfluxPrecision {
+    S,
+    Ms,
+    Us,
+    Ns,
+}
+
+impl InfluxPrecision {
+    fn from_env() -> Self {
+        match env::var("INFLUX_PRECISION").ok().as_deref() {
+            Some("s") => InfluxPrecision::S,
+            Some("ms") => InfluxPrecision::Ms,
+            Some("us") => InfluxPrecision::Us,
+            _ => InfluxPrecision::Ns,
+        }
+    }
+
+    fn query_param(&self) -> &'static str {
+        match self {
+            InfluxPrecision::S => "s",
+            InfluxPrecision::Ms => "ms",
+            InfluxPrecision::Us => "us",
+            InfluxPrecision::Ns => "ns",
+        }
+    }
+
+    /// Pembagi dari nanodetik (satuan `now_nanos()`) ke presisi ini.
+    fn divisor(&self) -> i128 {
+        match self {
+            InfluxPrecision::S => 1_000_000_000,
+            InfluxPrecision::Ms => 1_000_000,
+            InfluxPrecision::Us => 1_000,
+            InfluxPrecision::Ns => 1,
+        }
+    }
+}
+
+const MAX_BATCH_BUFFER: usize = 10_000;
+
+// ========================= Spool disk (write-ahead) =========================
+fn spool_append(path: &str, lines: &[String]) -> Result<()> {
+    use std::fs::OpenOptions;
+    let mut f = OpenOptions::new().create(true).append(true).open(path)
+        .with_context(|| format!("Gagal membuka spool {}", path))?;
+    for line in lines {
+        writeln!(f, "{}", line)?;
+    }
+    Ok(())
+}
+
+// Dipanggil dari main loop tiap 30s (lihat `last_spool_drain`) -- dua batas
+// ini menjaga satu pass tidak menahan polling serial/Modbus terlalu lama
+// saat InfluxDB down berkepanjangan, ketika SEMUA baris gagal dan
+// post_line sendiri sudah retry `cfg.retry.max_attempts` kali per baris
+// dengan backoff. Begitu salah satu batas tercapai, sisa baris di spool
+// ditulis ulang tanpa dicoba kirim dulu (ditangani pass berikutnya).
+const SPOOL_DRAIN_MAX_LINES_PER_PASS: usize = 200;
+const SPOOL_DRAIN_TIME_BUDGET: Duration = Duration::from_secs(5);
+
+/// Mencoba mengirim ulang baris di file spool satu per satu, dibatasi
+/// `SPOOL_DRAIN_MAX_LINES_PER_PASS`/`SPOOL_DRAIN_TIME_BUDGET` per panggilan,
+/// dan benar-benar streaming: baris yang tidak (atau belum) dicoba ditulis
+/// langsung ke berkas sementara lewat `BufRead::read_line` satu baris pada
+/// satu waktu, tidak pernah dikumpulkan jadi satu `Vec`/`String` besar --
+/// saat outage panjang dan semua baris gagal, ini yang mencegah seluruh
+/// spool numpuk di memori alih-alih hanya di disk.
+fn spool_drain(client: &Client, cfg: &Config, urls: &[String], path: &str) -> Result<usize> {
+    if !std::path::Path::new(path).exists() {
+        return Ok(0);
+    }
+    let f = std::fs::File::open(path).with_context(|| format!("Gagal membuka spool {}", path))?;
+    let mut reader = BufReader::new(f);
+    let tmp_path = format!("{}.draining", path);
+    let mut out = std::fs::File::create(&tmp_path)
+        .with_context(|| format!("Gagal membuka berkas sementara {}", tmp_path))?;
+
+    let mut drained = 0usize;
+    let mut kept = 0usize;
+    let mut attempted = 0usize;
+    let mut budget_exceeded = false;
+    let start = Instant::now();
+    let mut buf = String::new();
+
+    loop {
+        buf.clear();
+        let read = reader.read_line(&mut buf).with_context(|| format!("Gagal membaca spool {}", path))?;
+        if read == 0 {
+            break;
+        }
+        let line = buf.trim_end_matches(['\r', '\n']);
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        if !budget_exceeded && (attempted >= SPOOL_DRAIN_MAX_LINES_PER_PASS || start.elapsed() >= SPOOL_DRAIN_TIME_BUDGET) {
+            budget_exceeded = true;
+        }
+
+        if budget_exceeded {
+            writeln!(out, "{}", line)?;
+            kept += 1;
+            continue;
+        }
+
+        attempted += 1;
+        match post_line(client, cfg, urls, line) {
+            Ok(()) => drained += 1,
+            Err(_) => {
+                writeln!(out, "{}", line)?;
+                kept += 1;
+            }
+        }
+    }
+    drop(out);
+
+    if kept == 0 {
+        std::fs::remove_file(&tmp_path).ok();
+        std::fs::remove_file(path).ok();
+    } else {
+        std::fs::rename(&tmp_path, path).with_context(|| format!("Gagal menimpa spool {} dari berkas sementara", path))?;
+    }
+    Ok(drained)
+}
+
+// ========================= Sink CSV lokal =========================
+/// Sink opsional untuk tim QA yang ingin riwayat reading di file lokal tanpa
+/// perlu query Influx. Rotasi harian lewat nama file (`{CSV_PATH}.YYYY-MM-DD.csv`)
+/// supaya file tidak tumbuh tanpa batas; flush tiap baris karena ini dipakai
+/// untuk forensik pasca-crash, bukan jalur throughput tinggi.
+struct CsvSink {
+    base_path: String,
+    current_date: Option<chrono::NaiveDate>,
+    writer: Option<csv::Writer<std::fs::File>>,
+}
+
+impl CsvSink {
+    fn new(base_path: String) -> Self {
+        Self { base_path, current_date: None, writer: None }
+    }
+
+    fn path_for(&self, date: chrono::NaiveDate) -> String {
+        format!("{}.{}.csv", self.base_path, date.format("%Y-%m-%d"))
+    }
+
+    fn write_row(&mut self, ts: DateTime<Utc>, source: &str, temperature: f64, humidity: f64) -> Result<()> {
+        let date = ts.date_naive();
+        if self.current_date != Some(date) {
+            let path = self.path_for(date);
+            let need_header = !std::path::Path::new(&path).exists();
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .with_context(|| format!("Gagal membuka CSV {}", path))?;
+            let mut writer = csv::WriterBuilder::new().has_headers(false).from_writer(file);
+            if need_header {
+                writer.write_record(["timestamp", "source", "temperature", "humidity"])?;
+                writer.flush()?;
+            }
+            self.writer = Some(writer);
+            self.current_date = Some(date);
+        }
+
+        let writer = self.writer.as_mut().expect("writer selalu terisi setelah blok rotasi di atas");
+        writer.write_record([
+            &ts.to_rfc3339(),
+            source,
+            &temperature.to_string(),
+            &humidity.to_string(),
+        ])?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+fn nanos_to_datetime(ns: i128) -> DateTime<Utc> {
+    let secs = (ns / 1_000_000_000) as i64;
+    let nanos = (ns % 1_000_000_000) as u32;
+    DateTime::from_timestamp(secs, nanos).unwrap_or_else(Utc::now)
+}
+
+// ========================= Circuit breaker =========================
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BreakerState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+/// Memutus percobaan tulis ke Influx setelah `threshold` kegagalan
+/// `post_batch` berturut-turut, supaya loop serial tidak tertahan oleh HTTP
+/// call yang pasti gagal berulang-ulang saat Influx down -- baris tetap
+/// disimpan lewat jalur spool seperti biasa selama breaker terbuka. Setelah
+/// `cooldown`, satu percobaan probe (HalfOpen) dilepas; sukses menutup
+/// breaker lagi, gagal membukanya lagi dan mereset jam cooldown.
+struct CircuitBreaker {
+    state: BreakerState,
+    consecutive_failures: u32,
+    opened_at: Option<Instant>,
+    threshold: u32,
+    cooldown: Duration,
+}
+
+impl CircuitBreaker {
+    fn new(threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            state: BreakerState::Closed,
+            consecutive_failures: 0,
+            opened_at: None,
+            threshold: threshold.max(1),
+            cooldown,
+        }
+    }
+
+    /// Apakah percobaan tulis boleh dilakukan sekarang. Sekali cooldown
+    /// habis, breaker pindah ke HalfOpen dan melepas TEPAT SATU probe --
+    /// panggilan ini sendiri yang memindahkan state, jadi hanya boleh
+    /// dipanggil sekali per keputusan flush.
+    fn allow_request(&mut self) -> bool {
+        match self.state {
+            BreakerState::Closed | BreakerState::HalfOpen => true,
+            BreakerState::Open => {
+                let cooled_down = self.opened_at.map(|t| t.elapsed() >= self.cooldown).unwrap_or(true);
+                if cooled_down {
+                    self.state = BreakerState::HalfOpen;
+                    true
+                } else {
+                    false
+                }
+            }
+        }
+    }
+
+    fn record_success(&mut self) {
+        if self.state != BreakerState::Closed {
+            info!("Circuit breaker Influx menutup kembali setelah probe sukses");
+        }
+        self.consecutive_failures = 0;
+        self.state = BreakerState::Closed;
+        self.opened_at = None;
+    }
+
+    fn record_failure(&mut self) {
+        self.consecutive_failures += 1;
+        let should_open = self.state == BreakerState::HalfOpen || self.consecutive_failures >= self.threshold;
+        if should_open && self.state != BreakerState::Open {
+            warn!(
+                "Circuit breaker Influx terbuka setelah {} kegagalan berturut-turut, menulis lewat spool selama {:?}",
+                self.consecutive_failures, self.cooldown
+            );
+        }
+        if should_open {
+            self.state = BreakerState::Open;
+            self.opened_at = Some(Instant::now());
+        }
+    }
+}
+
+// ========================= Buffer batch =========================
+struct LineBuffer {
+    lines: Vec<String>,
+    last_flush: Instant,
+    breaker: CircuitBreaker,
+}
+
+impl LineBuffer {
+    fn new(cb_threshold: u32, cb_cooldown: Duration) -> Self {
+        Self {
+            lines: Vec::new(),
+            last_flush: Instant::now(),
+            breaker: CircuitBreaker::new(cb_threshold, cb_cooldown),
+        }
+    }
+
+    fn push(&mut self, line: String) {
+        if self.lines.len() < MAX_BATCH_BUFFER {
+            self.lines.push(line);
+        } else {
+            warn!("Buffer batch penuh ({} baris), membuang data terbaru", MAX_BATCH_BUFFER);
+        }
+    }
+
+    fn should_flush(&self, cfg: &Config) -> bool {
+        !self.lines.is_empty()
+            && (self.lines.len() >= cfg.batch_size || self.last_flush.elapsed() >= cfg.batch_interval)
+    }
+
+    /// Mengirim seluruh isi buffer; hanya mengosongkannya jika kirim berhasil
+    /// atau baris sudah tersimpan ke spool (`SPOOL_PATH`), sehingga data tidak
+    /// hilang saat Influx sedang down.
+    fn flush(&mut self, client: &Client, cfg: &Config, urls: &[String], metrics: &Metrics) {
+        if self.lines.is_empty() {
+            return;
+        }
+        if !self.breaker.allow_request() {
+            debug!("Circuit breaker Influx masih terbuka, langsung spool ({} baris)", self.lines.len());
+            if let Some(path) = &cfg.spool_path {
+                if let Err(spool_err) = spool_append(path, &self.lines) {
+                    error!("Gagal menulis spool {}: {}", path, spool_err);
+                } else {
+                    self.lines.clear();
+                }
+            }
+            self.last_flush = Instant::now();
+            return;
+        }
+        match post_batch(client, cfg, urls, &self.lines) {
+            Ok(()) => {
+                info!("OK Influx (batch): {} baris", self.lines.len());
+                metrics.influx_writes_total.fetch_add(1, Ordering::Relaxed);
+                self.breaker.record_success();
+                self.lines.clear();
+            }
+            Err(e) => {
+                error!("Gagal flush batch ({} baris tertahan): {}", self.lines.len(), e);
+                metrics.influx_write_failures_total.fetch_add(1, Ordering::Relaxed);
+                self.breaker.record_failure();
+                if let Some(path) = &cfg.spool_path {
+                    if let Err(spool_err) = spool_append(path, &self.lines) {
+                        error!("Gagal menulis spool {}: {}", path, spool_err);
+                    } else {
+                        self.lines.clear();
+                    }
+                }
+            }
+        }
+        self.last_flush = Instant::now();
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct RetryConfig {
+    max_attempts: u32,
+    base_delay: Duration,
+}
+
+impl RetryConfig {
+    fn from_env() -> Self {
+        Self {
+            max_attempts: env::var("INFLUX_RETRY_MAX").ok().and_then(|s| s.parse::<u32>().ok()).unwrap_or(3),
+            base_delay: Duration::from_millis(
+                env_duration_ms("INFLUX_RETRY_BASE_MS", 250),
+            ),
+        }
+    }
+
+    fn delay_for_attempt(&self, attempt: u32) -> Duration {
+        let capped_shift = attempt.min(6);
+        self.base_delay.saturating_mul(1u32 << capped_shift).min(Duration::from_secs(10))
+    }
+}
+
+/// Baca kredensial dari file kalau `{name}_FILE` diset (pola Docker/K8s
+/// secret mount), fallback ke variabel env polos `{name}`. File diprioritaskan
+/// kalau keduanya ada, karena itu cara sengaja mengganti nilai plain di
+/// environment tanpa perlu menghapusnya.
+fn read_secret_env(name: &str) -> Option<String> {
+    let file_var = format!("{}_FILE", name);
+    if let Ok(path) = env::var(&file_var) {
+        match std::fs::read_to_string(&path) {
+            Ok(contents) => return Some(contents.trim_end_matches(['\r', '\n']).to_string()),
+            Err(e) => warn!("Gagal membaca {} dari {} ({}): {}", name, file_var, path, e),
+        }
+    }
+    env::var(name).ok()
+}
+
+/// Baca env var durasi lewat `parse_duration_ms` (menerima suffix `500ms`,
+/// `2s`, `5m`, `1h`, atau angka polos ditafsir milidetik) dengan fallback ke
+/// `default`; env var yang ada tapi tidak bisa diparse juga jatuh ke
+/// `default` (bukan panic) supaya typo di `.env` tidak menghentikan startup.
+fn env_duration_ms(key: &str, default: u64) -> u64 {
+    env::var(key)
+        .ok()
+        .and_then(|s| parse_duration_ms(&s))
+        .unwrap_or(default)
 }
 
 impl Config {
     fn from_env() -> Result<Self> {
-        let influx_url = env::var("INFLUX_URL").context("INFLUX_URL not set")?;
-        let influx_token = env::var("INFLUX_TOKEN").context("INFLUX_TOKEN not set")?;
+        // DRY_RUN dibaca lebih dulu karena melonggarkan validasi token di bawah:
+        // kontributor baru bisa menjalankan bridge untuk cek parsing tanpa
+        // harus punya kredensial InfluxDB/ThingsBoard yang valid.
+        let dry_run = env::var("DRY_RUN").map(|v| v == "1" || v.eq_ignore_ascii_case("true")).unwrap_or(false);
+
+        // Default true demi kompatibilitas: deployment lama tanpa TB_ENABLED
+        // tetap mewajibkan TB_HOST/TB_TOKEN seperti sebelumnya.
+        let tb_enabled = env::var("TB_ENABLED").map(|v| v == "1" || v.eq_ignore_ascii_case("true")).unwrap_or(true);
+
+        let influx_url_raw = env::var("INFLUX_URL").context("INFLUX_URL not set")?;
+        let influx_urls = parse_influx_urls(&influx_url_raw);
+        if influx_urls.is_empty() {
+            return Err(anyhow!("INFLUX_URL tidak berisi endpoint yang valid"));
+        }
+        let influx_version = match env::var("INFLUX_VERSION").ok().as_deref() {
+            Some("v1") => InfluxVersion::V1,
+            _ => InfluxVersion::V2,
+        };
+        let influx_token = match influx_version {
+            InfluxVersion::V2 if dry_run => read_secret_env("INFLUX_TOKEN").unwrap_or_default(),
+            InfluxVersion::V2 => read_secret_env("INFLUX_TOKEN").context("INFLUX_TOKEN not set")?,
+            InfluxVersion::V1 => read_secret_env("INFLUX_TOKEN").unwrap_or_default(),
+        };
         let influx_org = env::var("INFLUX_ORG").context("INFLUX_ORG not set")?;
+        let influx_org_id = env::var("INFLUX_ORG_ID").ok();
         let influx_bucket = env::var("INFLUX_BUCKET").context("INFLUX_BUCKET not set")?;
+        let measurement = env::var("MEASUREMENT").unwrap_or_else(|_| "sensor".into());
 
         Ok(Self {
-            influx_url,
+            influx_urls,
             influx_token,
             influx_org,
+            influx_org_id,
             influx_bucket,
-            measurement: env::var("MEASUREMENT").unwrap_or_else(|_| "sensor".into()),
+            // MEASUREMENT_RAW default ke measurement yang sama supaya perilaku
+            // lama (satu measurement untuk semua) tetap jalan tanpa env baru.
+            measurement_raw: env::var("MEASUREMENT_RAW").unwrap_or_else(|_| measurement.clone()),
+            measurement,
             tag_source: env::var("TAG_SOURCE").unwrap_or_else(|_| "COM15".into()),
             serial_port: env::var("SERIAL_PORT").unwrap_or_else(|_| "COM15".into()),
             baudrate: env::var("BAUDRATE").ok().and_then(|s| s.parse::<u32>().ok()).unwrap_or(115200),
             include_raw_on_fail: env::var("INCLUDE_RAW_ON_FAIL").map(|v| v == "1" || v.eq_ignore_ascii_case("true")).unwrap_or(true),
 
-            tb_host: env::var("TB_HOST").context("TB_HOST not set")?,
+            tb_enabled,
+            // Default topic TB biar biner yang sama bisa mendorong ke
+            // Mosquitto/broker generik lain sekadar dengan mengganti satu env var.
+            mqtt_topic: env::var("MQTT_TOPIC").unwrap_or_else(|_| "v1/devices/me/telemetry".into()),
+            // Default LWT memakai konvensi shared attribute TB: broker
+            // mempublish ini sendiri begitu koneksi putus ungracefully
+            // (crash, cabel, dsb), jadi TB menandai device inactive tanpa
+            // menunggu timeout inactivity default-nya.
+            mqtt_lwt_topic: env::var("MQTT_LWT_TOPIC").unwrap_or_else(|_| "v1/devices/me/attributes".into()),
+            mqtt_lwt_payload: env::var("MQTT_LWT_PAYLOAD").unwrap_or_else(|_| r#"{"status":"offline"}"#.into()),
+            tb_host: if tb_enabled {
+                env::var("TB_HOST").context("TB_HOST not set")?
+            } else {
+                String::new()
+            },
             tb_port: env::var("TB_PORT").ok().and_then(|s| s.parse::<u16>().ok()).unwrap_or(1883),
-            tb_token: env::var("TB_TOKEN").context("TB_TOKEN not set")?,
+            tb_token: if !tb_enabled || dry_run {
+                read_secret_env("TB_TOKEN").unwrap_or_default()
+            } else {
+                read_secret_env("TB_TOKEN").context("TB_TOKEN not set")?
+            },
             tb_client_id: env::var("TB_CLIENT_ID").unwrap_or_else(|_| "influx-bridge".into()),
             tb_use_tls: env::var("TB_USE_TLS").map(|v| v == "1" || v.eq_ignore_ascii_case("true")).unwrap_or(false),
+
+            retry: RetryConfig::from_env(),
+            batch_size: env::var("INFLUX_BATCH_SIZE").ok().and_then(|s| s.parse::<usize>().ok()).unwrap_or(1),
+            batch_interval: Duration::from_millis(
+                env_duration_ms("INFLUX_BATCH_INTERVAL_MS", 1000),
+            ),
+            spool_path: env::var("SPOOL_PATH").ok(),
+            influx_version,
+            influx_user: env::var("INFLUX_USER").ok(),
+            influx_password: env::var("INFLUX_PASSWORD").ok(),
+            pair_timeout: Duration::from_millis(
+                env_duration_ms("PAIR_TIMEOUT_MS", 5000),
+            ),
+            temp_unit: match env::var("TEMP_UNIT").ok().as_deref() {
+                Some("F") | Some("f") => TempUnit::Fahrenheit,
+                _ => TempUnit::Celsius,
+            },
+            influx_bucket_raw: env::var("INFLUX_BUCKET_RAW").ok(),
+            metrics_port: env::var("METRICS_PORT").ok().and_then(|s| s.parse::<u16>().ok()),
+            query_range: env::var("QUERY_RANGE").unwrap_or_else(|_| "-1h".into()),
+            field_temperature: env::var("QUERY_FIELD_TEMPERATURE").unwrap_or_else(|_| "temperature".into()),
+            field_humidity: env::var("QUERY_FIELD_HUMIDITY").unwrap_or_else(|_| "humidity".into()),
+            input_mode: match env::var("INPUT_MODE").ok().as_deref() {
+                Some("tcp") => InputMode::Tcp,
+                Some("file") => InputMode::File,
+                _ => InputMode::Serial,
+            },
+            input_tcp_addr: env::var("INPUT_TCP_ADDR").ok(),
+            input_file: env::var("INPUT_FILE").ok(),
+            dry_run,
+            temp_min: env::var("TEMP_MIN").ok().and_then(|s| s.parse::<f64>().ok()).unwrap_or(-40.0),
+            temp_max: env::var("TEMP_MAX").ok().and_then(|s| s.parse::<f64>().ok()).unwrap_or(125.0),
+            timestamp_source: match env::var("INFLUX_TIMESTAMP_SOURCE").ok().as_deref() {
+                Some("none") => TimestampSource::None,
+                _ => TimestampSource::Host,
+            },
+            influx_precision: InfluxPrecision::from_env(),
+            emit_integers: env::var("EMIT_INTEGERS").map(|v| v == "1" || v.eq_ignore_ascii_case("true")).unwrap_or(false),
+            dedup: env::var("DEDUP").map(|v| v == "1" || v.eq_ignore_ascii_case("true")).unwrap_or(false),
+            dedup_max_gap_ms: env_duration_ms("DEDUP_MAX_GAP_MS", 5 * 60 * 1000),
+            smooth_alpha: env::var("SMOOTH_ALPHA").ok().and_then(|s| s.parse::<f64>().ok()).filter(|a| (0.0..=1.0).contains(a)),
+            smooth_apply_to: match env::var("SMOOTH_APPLY_TO").ok().as_deref() {
+                Some("influx") => SmoothTarget::Influx,
+                Some("both") => SmoothTarget::Both,
+                _ => SmoothTarget::Tb,
+            },
+            influx_ca_cert: env::var("INFLUX_CA_CERT").ok(),
+            influx_insecure: env::var("INFLUX_INSECURE").map(|v| v == "1" || v.eq_ignore_ascii_case("true")).unwrap_or(false),
+            tb_qos: match env::var("TB_QOS").ok().as_deref() {
+                Some("0") => QoS::AtMostOnce,
+                Some("2") => QoS::ExactlyOnce,
+                _ => QoS::AtLeastOnce,
+            },
+            tb_retain: env::var("TB_RETAIN").map(|v| v == "1" || v.eq_ignore_ascii_case("true")).unwrap_or(false),
+            tb_include_ts: env::var("TB_INCLUDE_TS").map(|v| v == "1" || v.eq_ignore_ascii_case("true")).unwrap_or(false),
+            tb_source: match env::var("TB_SOURCE").ok().as_deref() {
+                Some("direct") => TbSource::Direct,
+                _ => TbSource::Influx,
+            },
+            // Kalau retry query_latest_influx tetap gagal total, TB_SOURCE=influx
+            // biasanya melewatkan publish TB siklus ini (lihat komentar di loop
+            // utama); set ini ke true untuk jatuh ke nilai yang baru diparse
+            // (perilaku TB_SOURCE=direct) alih-alih diam sama sekali.
+            tb_query_fallback_to_direct: env::var("TB_QUERY_FALLBACK_TO_DIRECT")
+                .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+                .unwrap_or(false),
+            extra_tags: env::var("INFLUX_EXTRA_TAGS").map(|s| parse_extra_tags(&s)).unwrap_or_default(),
+            heartbeat_interval_ms: env::var("HEARTBEAT_INTERVAL_MS").ok().and_then(|s| parse_duration_ms(&s)),
+            output_json: env::var("OUTPUT_JSON").map(|v| v == "1" || v.eq_ignore_ascii_case("true")).unwrap_or(false),
+            csv_path: env::var("CSV_PATH").ok(),
+            tb_publish_settle_ms: env_duration_ms("TB_PUBLISH_SETTLE_MS", 150),
+            tb_publish_delta: env::var("TB_PUBLISH_DELTA").ok().and_then(|s| s.parse::<f64>().ok()),
+            tb_max_interval_ms: env_duration_ms("TB_MAX_INTERVAL_MS", 60_000),
+            field_units: env::var("FIELD_UNITS").map(|s| parse_field_units(&s)).unwrap_or_default(),
+            influx_gzip: env::var("INFLUX_GZIP").map(|v| v == "1" || v.eq_ignore_ascii_case("true")).unwrap_or(false),
+            tb_include_raw: env::var("TB_INCLUDE_RAW").map(|v| v == "1" || v.eq_ignore_ascii_case("true")).unwrap_or(false),
+            cb_failure_threshold: env::var("CB_FAILURE_THRESHOLD").ok().and_then(|s| s.parse::<u32>().ok()).unwrap_or(5),
+            cb_cooldown: Duration::from_millis(
+                env_duration_ms("CB_COOLDOWN_MS", 30_000),
+            ),
+            round_decimals: env::var("ROUND_DECIMALS").ok().and_then(|s| s.parse::<u32>().ok()),
+            startup_discard: Duration::from_millis(
+                env_duration_ms("STARTUP_DISCARD_MS", 0),
+            ),
+            banner_regex: match env::var("BANNER_REGEX").ok() {
+                Some(pat) => Some(Regex::new(&pat).context("BANNER_REGEX bukan regex yang valid")?),
+                None => None,
+            },
+            agg_window: Duration::from_millis(
+                env_duration_ms("AGG_WINDOW_MS", 0),
+            ),
+            allowed_fields: env::var("ALLOWED_FIELDS").ok().map(|s| {
+                s.split(',').map(|f| f.trim().to_string()).filter(|f| !f.is_empty()).collect()
+            }),
+            // FIELD_MAP (`0:temperature,1:humidity,2:pressure`) menamai CSV
+            // posisional dari firmware yang mencetak nilai mentah tanpa key;
+            // kosong (default) berarti parser CSV posisional ini tidak pernah
+            // aktif, baris tetap jatuh ke fallback Single Number/Raw seperti
+            // sebelum FIELD_MAP ada.
+            field_map: env::var("FIELD_MAP").map(|s| parse_field_map(&s)).unwrap_or_default(),
         })
     }
 }
@@ -177,24 +894,262 @@ fn open_serial(port: &str, baud: u32) -> Result<Box<dyn serialport::SerialPort>>
         .with_context(|| format!("Gagal membuka serial {} @{}", port, baud))
 }
 
-fn build_write_url(cfg: &Config) -> String {
-    format!(
-        "{}/api/v2/write?org={}&bucket={}&precision=ns",
-        cfg.influx_url.trim_end_matches('/'),
-        urlencoding::encode(&cfg.influx_org),
-        urlencoding::encode(&cfg.influx_bucket)
-    )
+fn open_tcp(addr: &str) -> Result<std::net::TcpStream> {
+    std::net::TcpStream::connect(addr).with_context(|| format!("Gagal konek TCP {}", addr))
 }
 
-fn post_line(client: &Client, cfg: &Config, url: &str, line: &str) -> Result<()> {
-    let resp = client
-        .post(url)
-        .bearer_auth(&cfg.influx_token)
-        .header("Content-Type", "text/plain; charset=utf-8")
-        .body(line.to_string())
+/// Membuka sumber data sesuai `INPUT_MODE`: serial (default, perilaku lama),
+/// TCP saat ESP streaming lewat WiFi, atau file/stdin (`INPUT_FILE`, `-` =
+/// stdin) untuk replay log. Ketiganya dibungkus jadi trait
+/// object `Read` yang sama supaya loop parsing di `main` tidak perlu tahu bedanya.
+fn open_input(cfg: &Config) -> Result<Box<dyn std::io::Read + Send>> {
+    match cfg.input_mode {
+        InputMode::Serial => Ok(Box::new(open_serial(&cfg.serial_port, cfg.baudrate)?)),
+        InputMode::Tcp => {
+            let addr = cfg.input_tcp_addr.as_deref()
+                .context("INPUT_TCP_ADDR not set untuk INPUT_MODE=tcp")?;
+            Ok(Box::new(open_tcp(addr)?))
+        }
+        InputMode::File => {
+            let path = cfg.input_file.as_deref()
+                .context("INPUT_FILE not set untuk INPUT_MODE=file")?;
+            if path == "-" {
+                Ok(Box::new(std::io::stdin()))
+            } else {
+                Ok(Box::new(std::fs::File::open(path)
+                    .with_context(|| format!("Gagal membuka INPUT_FILE {}", path))?))
+            }
+        }
+    }
+}
+
+/// Jitter +-20% di atas backoff dasar supaya banyak unit yang kehilangan USB
+/// bersamaan (mis. setelah power cycle panel) tidak semua mencoba reconnect
+/// di detik yang sama persis. Dipakai jam sistem sendiri sebagai sumber acak
+/// murah, tidak perlu dependency `rand` untuk kebutuhan sekecil ini.
+fn jitter(delay: Duration) -> Duration {
+    let nanos = now_nanos() as u64;
+    let spread = (nanos % 41) as i64 - 20; // -20..=20 (%)
+    let factor = 100i64 + spread;
+    Duration::from_millis((delay.as_millis() as i64 * factor / 100).max(0) as u64)
+}
+
+/// Menyambung ulang serial dengan backoff eksponensial (500ms, 1s, 2s, ...
+/// maks 30s, plus jitter) sampai device muncul lagi. Dipakai saat USB
+/// dicabut lalu dipasang ulang dan path-nya berubah/hilang sementara.
+fn reconnect_serial_input(cfg: &Config) -> Result<BufReader<Box<dyn std::io::Read + Send>>> {
+    let mut delay = Duration::from_millis(500);
+    let mut attempt: u32 = 0;
+    loop {
+        attempt += 1;
+        match open_serial(&cfg.serial_port, cfg.baudrate) {
+            Ok(sp) => {
+                info!("Serial {} tersambung kembali (percobaan ke-{})", cfg.serial_port, attempt);
+                return Ok(BufReader::new(Box::new(sp)));
+            }
+            Err(e) => {
+                let wait = jitter(delay);
+                warn!(
+                    "Gagal menyambung ulang serial {} (percobaan ke-{}): {}, retry dalam {:?}",
+                    cfg.serial_port, attempt, e, wait
+                );
+                std::thread::sleep(wait);
+                delay = (delay * 2).min(Duration::from_secs(30));
+            }
+        }
+    }
+}
+
+/// Menyambung ulang input TCP dengan backoff tetap sampai peer hidup lagi.
+/// Dipakai saat `reader.read_line` gagal di tengah loop utama karena peer drop.
+fn reconnect_tcp_input(cfg: &Config) -> Result<BufReader<Box<dyn std::io::Read + Send>>> {
+    let addr = cfg.input_tcp_addr.as_deref()
+        .context("INPUT_TCP_ADDR not set untuk INPUT_MODE=tcp")?;
+    loop {
+        match open_tcp(addr) {
+            Ok(stream) => {
+                info!("TCP input tersambung kembali ke {}", addr);
+                return Ok(BufReader::new(Box::new(stream)));
+            }
+            Err(e) => {
+                warn!("Gagal menyambung ulang TCP {}: {}, retry dalam 2s", addr, e);
+                std::thread::sleep(Duration::from_secs(2));
+            }
+        }
+    }
+}
+
+/// Baris serial normal cuma puluhan byte; batas ini hanya jaring pengaman
+/// untuk sensor nyasar/misconfigured yang mengoceh tanpa newline, supaya
+/// `buf` tidak tumbuh tanpa batas dan meng-OOM bridge.
+const MAX_LINE_BYTES: usize = 4096;
+
+/// Baca satu baris lewat `fill_buf`/`consume` (bukan `read_line`) supaya kita
+/// bisa memeriksa panjang mentahnya sebelum didekode jadi `String`; begitu
+/// `raw_buf` melewati `max_bytes`, sisa baris tetap dikonsumsi sampai newline
+/// tapi tidak lagi disalin. Baris yang melebihi `max_bytes` dibuang dengan
+/// warning tanpa ditambahkan ke `line_buf` -- caller memperlakukannya sama
+/// seperti baris kosong biasa. Karena pembacaan selalu berhenti tepat setelah
+/// byte newline (atau EOF), pemanggilan berikutnya otomatis mulai lagi dari
+/// baris baru tanpa logika resync tambahan.
+fn read_line_capped(
+    reader: &mut impl BufRead,
+    line_buf: &mut String,
+    raw_buf: &mut Vec<u8>,
+    max_bytes: usize,
+) -> std::io::Result<usize> {
+    raw_buf.clear();
+    let mut n = 0usize;
+    loop {
+        let available = reader.fill_buf()?;
+        let (len, done) = match available.iter().position(|&b| b == b'\n') {
+            Some(i) => (i + 1, true),
+            None => (available.len(), available.is_empty()),
+        };
+        if raw_buf.len() <= max_bytes {
+            raw_buf.extend_from_slice(&available[..len]);
+        }
+        reader.consume(len);
+        n += len;
+        if done { break; }
+    }
+    if n == 0 {
+        return Ok(0);
+    }
+    if raw_buf.len() > max_bytes {
+        warn!("Baris serial melebihi MAX_LINE_BYTES ({} > {} byte), dibuang", n, max_bytes);
+        return Ok(n);
+    }
+    match std::str::from_utf8(raw_buf) {
+        Ok(s) => line_buf.push_str(s),
+        Err(_) => {
+            // Byte noise atau frame Modbus biner nyasar ke port yang sama --
+            // konversi lossy supaya satu byte rusak tidak mematikan loop
+            // pembacaan serial.
+            let lossy = String::from_utf8_lossy(raw_buf);
+            warn!("Baris serial mengandung byte non-UTF-8, diganti dengan U+FFFD: {:?}", lossy);
+            line_buf.push_str(&lossy);
+        }
+    }
+    Ok(n)
+}
+
+/// Influx Cloud kadang butuh `orgID=<id hex>` alih-alih `org=<nama>` di URL
+/// write/query -- `INFLUX_ORG_ID` kalau diset selalu menang karena itu
+/// override eksplisit, bukan fallback.
+fn org_query_param(org: &str, org_id: Option<&str>) -> String {
+    match org_id {
+        Some(id) => format!("orgID={}", urlencoding::encode(id)),
+        None => format!("org={}", urlencoding::encode(org)),
+    }
+}
+
+/// `STARTUP_DISCARD_MS=0` (default) berarti tidak pernah membuang apa pun --
+/// dipisah jadi fungsi murni agar jendela disable-nya bisa diuji tanpa sleep.
+fn is_within_startup_discard(elapsed: Duration, startup_discard: Duration) -> bool {
+    startup_discard > Duration::ZERO && elapsed < startup_discard
+}
+
+/// `INFLUX_URL` boleh "http://a:8086,http://b:8086" (primary,standby) --
+/// dipisah koma, spasi di sekitar tiap endpoint dibuang, entri kosong
+/// (trailing comma dsb) tidak ikut masuk.
+fn parse_influx_urls(raw: &str) -> Vec<String> {
+    raw.split(',').map(|s| s.trim().to_string()).filter(|s| !s.is_empty()).collect()
+}
+
+fn build_write_url(cfg: &Config, base_url: &str, bucket: &str) -> String {
+    let precision = cfg.influx_precision.query_param();
+    match cfg.influx_version {
+        InfluxVersion::V2 => format!(
+            "{}/api/v2/write?{}&bucket={}&precision={}",
+            base_url.trim_end_matches('/'),
+            org_query_param(&cfg.influx_org, cfg.influx_org_id.as_deref()),
+            urlencoding::encode(bucket),
+            precision
+        ),
+        InfluxVersion::V1 => format!(
+            "{}/write?db={}&precision={}",
+            base_url.trim_end_matches('/'),
+            urlencoding::encode(bucket),
+            precision
+        ),
+    }
+}
+
+/// `INFLUX_URL` boleh berisi beberapa endpoint dipisah koma (primary,standby)
+/// -- satu write URL dibangun per endpoint supaya `post_line` bisa gagal
+/// alih ke endpoint berikutnya tanpa load balancer eksternal.
+fn build_write_urls(cfg: &Config, bucket: &str) -> Vec<String> {
+    cfg.influx_urls.iter().map(|base| build_write_url(cfg, base, bucket)).collect()
+}
+
+// Batch di bawah ukuran ini tidak sepadan untuk digzip -- overhead header gzip
+// dan biaya CPU kompresi lebih besar daripada penghematan egress untuk satu
+// baris line-protocol yang pendek.
+const GZIP_MIN_BODY_BYTES: usize = 512;
+
+/// Abstraksi satu baris line-protocol dikirim ke satu URL, supaya
+/// `post_line_single` bisa diuji dengan transport palsu (lihat test
+/// `post_line_retries_until_success`) tanpa harus menyentuh HTTP sungguhan.
+/// `Client` (transport produksi, lewat `send_line`) adalah satu-satunya
+/// implementor di luar test.
+trait LineSender {
+    fn send_line(&self, cfg: &Config, url: &str, line: &str) -> Result<()>;
+}
+
+impl LineSender for Client {
+    fn send_line(&self, cfg: &Config, url: &str, line: &str) -> Result<()> {
+        send_line(self, cfg, url, line)
+    }
+}
+
+fn send_line(client: &Client, cfg: &Config, url: &str, line: &str) -> Result<()> {
+    let mut req = client.post(url).header("Content-Type", "text/plain; charset=utf-8");
+    req = match cfg.influx_version {
+        InfluxVersion::V2 => req.bearer_auth(&cfg.influx_token),
+        InfluxVersion::V1 => req.basic_auth(
+            cfg.influx_user.as_deref().unwrap_or(""),
+            cfg.influx_password.as_deref(),
+        ),
+    };
+
+    if cfg.influx_gzip && line.len() >= GZIP_MIN_BODY_BYTES {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder
+            .write_all(line.as_bytes())
+            .context("Gagal menggzip body sebelum kirim ke InfluxDB")?;
+        let compressed = encoder
+            .finish()
+            .context("Gagal menyelesaikan kompresi gzip body InfluxDB")?;
+        req = req.header("Content-Encoding", "gzip").body(compressed);
+    } else {
+        req = req.body(line.to_string());
+    }
+
+    let resp = req
         .send()
         .context("HTTP error saat kirim ke InfluxDB")?;
 
+    if resp.status() == reqwest::StatusCode::TOO_MANY_REQUESTS {
+        // InfluxDB Cloud (dan beberapa gateway di depannya) membalas 429 saat
+        // rate limit organisasi terlampaui, dengan `Retry-After` dalam detik.
+        // Tunggu sesuai arahan server (dibatasi MAX_RETRY_AFTER supaya satu
+        // respons nakal tidak menahan batch berikutnya berjam-jam) sebelum
+        // dianggap gagal & retryable seperti biasa -- bukan error permanen.
+        const MAX_RETRY_AFTER: Duration = Duration::from_secs(30);
+        let retry_after = resp
+            .headers()
+            .get("Retry-After")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|s| s.parse::<u64>().ok())
+            .map(Duration::from_secs)
+            .unwrap_or(Duration::from_secs(1))
+            .min(MAX_RETRY_AFTER);
+        warn!("InfluxDB membalas 429 (rate limited), menunggu {:?} sesuai Retry-After", retry_after);
+        std::thread::sleep(retry_after);
+        return Err(anyhow!("InfluxDB write failed: 429 => rate limited"));
+    }
+
     if !resp.status().is_success() {
         let code = resp.status();
         let text = resp.text().unwrap_or_default();
@@ -203,61 +1158,594 @@ fn post_line(client: &Client, cfg: &Config, url: &str, line: &str) -> Result<()>
     Ok(())
 }
 
-// ========================= Parser RH/T =========================
-fn update_pending_from_line(p: &mut Pending, line: &str) -> Option<(f64, f64)> {
-    let mut updated = false;
+fn is_retryable(err: &anyhow::Error) -> bool {
+    // Kegagalan koneksi (tidak ada respons HTTP) selalu layak di-retry. Untuk respons
+    // non-sukses kita baca kembali kode status dari pesan "InfluxDB write failed: <code> => ...".
+    let msg = err.to_string();
+    match msg.strip_prefix("InfluxDB write failed: ").and_then(|rest| rest.split_whitespace().next()) {
+        Some(code_str) => code_str.parse::<u16>().map(|c| c == 429 || (500..600).contains(&c)).unwrap_or(true),
+        None => true,
+    }
+}
+
+/// `--import <file>`: menulis ulang CSV `timestamp,source,temperature,humidity`
+/// (misalnya hasil CsvSink saat bridge sempat offline) ke InfluxDB memakai
+/// timestamp ASLI per baris, bukan `now_nanos`, supaya data historis tidak
+/// numpuk di satu titik waktu saat di-backfill.
+fn run_csv_import(client: &Client, cfg: &Config, path: &str) -> Result<usize> {
+    let write_urls = build_write_urls(cfg, &cfg.influx_bucket);
+    let mut rdr = csv::ReaderBuilder::new()
+        .has_headers(true)
+        .from_path(path)
+        .with_context(|| format!("Gagal membuka berkas import {}", path))?;
+
+    let headers = rdr.headers()?.clone();
+    let i_ts = headers.iter().position(|h| h == "timestamp")
+        .ok_or_else(|| anyhow!("Kolom timestamp tidak ada pada {}", path))?;
+    let i_source = headers.iter().position(|h| h == "source")
+        .ok_or_else(|| anyhow!("Kolom source tidak ada pada {}", path))?;
+    let i_temp = headers.iter().position(|h| h == "temperature")
+        .ok_or_else(|| anyhow!("Kolom temperature tidak ada pada {}", path))?;
+    let i_hum = headers.iter().position(|h| h == "humidity")
+        .ok_or_else(|| anyhow!("Kolom humidity tidak ada pada {}", path))?;
+
+    // Kumpulkan paling banyak `batch_size` baris sekaligus lalu langsung post,
+    // bukan menampung seluruh CSV ke memori dulu (lihat fix spool_drain di
+    // 7af3677 untuk alasan yang sama) -- backfill berbulan-bulan hasil
+    // CsvSink bisa jauh lebih besar daripada RAM yang wajar dialokasikan
+    // untuk satu proses `--import`.
+    let mut lines = Vec::new();
+    let mut imported = 0usize;
+    let batch_size = cfg.batch_size.max(1);
+    for rec in rdr.records() {
+        let rec = rec?;
+        let ts_str = rec.get(i_ts).unwrap_or("");
+        let source = rec.get(i_source).unwrap_or("");
+        let temp_str = rec.get(i_temp).unwrap_or("");
+        let hum_str = rec.get(i_hum).unwrap_or("");
 
-    if let Some(c) = RH_RE.captures(line) {
-        if let Some(m) = c.get(1) {
-            if let Ok(v) = m.as_str().parse::<f64>() {
-                p.rh = Some(v);
-                updated = true;
+        let ts: DateTime<Utc> = match ts_str.parse() {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Baris import dilewati, timestamp '{}' bukan RFC3339: {}", ts_str, e);
+                continue;
             }
+        };
+        let temp: f64 = match temp_str.parse() {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Baris import dilewati, temperature '{}' tidak valid: {}", temp_str, e);
+                continue;
+            }
+        };
+        let hum: f64 = match hum_str.parse() {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Baris import dilewati, humidity '{}' tidak valid: {}", hum_str, e);
+                continue;
+            }
+        };
+
+        let line = format!(
+            "{},source={} {}={},{}={} {}",
+            escape_measurement(&cfg.measurement),
+            escape_tag_key_or_value(source),
+            cfg.field_temperature,
+            temp,
+            cfg.field_humidity,
+            hum,
+            ts.timestamp_nanos_opt().unwrap_or(0) as i128 / cfg.influx_precision.divisor()
+        );
+        lines.push(line);
+        imported += 1;
+
+        if lines.len() >= batch_size {
+            post_batch(client, cfg, &write_urls, &lines)?;
+            lines.clear();
         }
     }
-    if let Some(c) = T_RE.captures(line) {
-        if let Some(m) = c.get(1) {
-            if let Ok(v) = m.as_str().parse::<f64>() {
-                p.t = Some(v);
-                updated = true;
+    post_batch(client, cfg, &write_urls, &lines)?;
+
+    Ok(imported)
+}
+
+fn post_batch(client: &Client, cfg: &Config, urls: &[String], lines: &[String]) -> Result<()> {
+    if lines.is_empty() {
+        return Ok(());
+    }
+    let body = lines.join("\n");
+    post_line(client, cfg, urls, &body)
+}
+
+/// Gagal total di satu endpoint (retry `cfg.retry` kali habis) pindah ke
+/// endpoint berikutnya di `urls`, dimulai dari `LAST_WORKING_ENDPOINT` supaya
+/// endpoint yang terakhir terbukti hidup selalu dicoba lebih dulu. Hanya
+/// mengembalikan error setelah SEMUA endpoint gagal.
+fn post_line(client: &Client, cfg: &Config, urls: &[String], line: &str) -> Result<()> {
+    let start_idx = LAST_WORKING_ENDPOINT.load(Ordering::Relaxed) % urls.len();
+    let mut last_err = None;
+    for offset in 0..urls.len() {
+        let idx = (start_idx + offset) % urls.len();
+        match post_line_single(client, cfg, &urls[idx], line) {
+            Ok(()) => {
+                LAST_WORKING_ENDPOINT.store(idx, Ordering::Relaxed);
+                return Ok(());
+            }
+            Err(e) => {
+                if offset + 1 < urls.len() {
+                    warn!("Endpoint Influx {} gagal, gagal alih ke endpoint berikutnya: {}", urls[idx], e);
+                }
+                last_err = Some(e);
             }
         }
     }
-    if updated {
-        if let (Some(rh), Some(t)) = (p.rh, p.t) {
-            return Some((rh, t));
+    Err(last_err.unwrap_or_else(|| anyhow!("post_line gagal tanpa error tercatat")))
+}
+
+fn post_line_single<S: LineSender>(client: &S, cfg: &Config, url: &str, line: &str) -> Result<()> {
+    if cfg.dry_run {
+        info!("[dry-run] Akan menulis ke Influx ({}): {}", url, line);
+        return Ok(());
+    }
+    let retry = cfg.retry;
+    let mut last_err = None;
+    for attempt in 0..retry.max_attempts.max(1) {
+        match client.send_line(cfg, url, line) {
+            Ok(()) => return Ok(()),
+            Err(e) => {
+                if attempt + 1 >= retry.max_attempts || !is_retryable(&e) {
+                    return Err(e);
+                }
+                let delay = retry.delay_for_attempt(attempt);
+                warn!("Retry post_line (attempt {}/{}) setelah {:?}: {}", attempt + 1, retry.max_attempts, delay, e);
+                std::thread::sleep(delay);
+                last_err = Some(e);
+            }
         }
     }
-    None
+    Err(last_err.unwrap_or_else(|| anyhow!("post_line gagal tanpa error tercatat")))
 }
 
-// ========================= Query Influx terbaru =========================
-#[derive(Debug)]
-struct Latest {
-    temperature: f64,
-    humidity: f64,
-    ts_ms: i64,
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn monotonic_nanos_bumps_on_backward_clock_step() {
+        let last = AtomicI64::new(1_000);
+        // Jam loncat mundur ke 500 (koreksi NTP) -- hasilnya harus tetap naik
+        // dari nilai terakhir, bukan mengikuti jam mundur.
+        assert_eq!(monotonic_nanos(&last, 500), 1_001);
+        // Panggilan berikutnya dengan jam yang masih di belakang nilai terakhir
+        // terus naik 1ns per panggilan, tidak pernah mundur atau diam di tempat.
+        assert_eq!(monotonic_nanos(&last, 500), 1_002);
+        // Begitu jam maju lagi melewati nilai terakhir, kita kembali mengikuti jam asli.
+        assert_eq!(monotonic_nanos(&last, 2_000), 2_000);
+    }
+
+    // Transport palsu yang gagal `fail_times` kali lalu sukses, supaya
+    // `post_line_single` bisa diuji ujung-ke-ujung (termasuk backoff &
+    // `is_retryable`) tanpa menyentuh HTTP sungguhan.
+    struct FlakyThenOkSender {
+        fail_times: u32,
+        attempts: Cell<u32>,
+    }
+
+    impl LineSender for FlakyThenOkSender {
+        fn send_line(&self, _cfg: &Config, _url: &str, _line: &str) -> Result<()> {
+            let attempt = self.attempts.get();
+            self.attempts.set(attempt + 1);
+            if attempt < self.fail_times {
+                Err(anyhow!("connection refused"))
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    // Config lengkap tapi minimal cuma untuk test: field-field di luar
+    // `retry`/`dry_run` tidak pernah dibaca oleh `post_line_single` +
+    // `FlakyThenOkSender`. Test `run_csv_import` juga membaca `influx_urls`
+    // (harus tidak kosong), `batch_size`, `measurement`, nama field, dan
+    // `influx_precision`; sisanya sembarang selama tipenya cocok.
+    fn minimal_config(retry: RetryConfig, dry_run: bool) -> Config {
+        Config {
+            influx_urls: vec!["http://example.invalid".into()],
+            influx_token: String::new(),
+            influx_org: String::new(),
+            influx_org_id: None,
+            influx_bucket: String::new(),
+            measurement: "sensor".into(),
+            tag_source: "host".into(),
+            serial_port: String::new(),
+            baudrate: 9600,
+            include_raw_on_fail: false,
+            tb_enabled: false,
+            mqtt_topic: String::new(),
+            mqtt_lwt_topic: String::new(),
+            mqtt_lwt_payload: String::new(),
+            tb_host: String::new(),
+            tb_port: 1883,
+            tb_token: String::new(),
+            tb_client_id: String::new(),
+            tb_use_tls: false,
+            retry,
+            batch_size: 1,
+            batch_interval: Duration::from_secs(1),
+            spool_path: None,
+            influx_version: InfluxVersion::V2,
+            influx_user: None,
+            influx_password: None,
+            pair_timeout: Duration::from_secs(1),
+            temp_unit: TempUnit::Celsius,
+            influx_bucket_raw: None,
+            metrics_port: None,
+            query_range: "-5m".into(),
+            field_temperature: "temperature".into(),
+            field_humidity: "humidity".into(),
+            input_mode: InputMode::Serial,
+            input_tcp_addr: None,
+            input_file: None,
+            dry_run,
+            temp_min: -40.0,
+            temp_max: 80.0,
+            timestamp_source: TimestampSource::Host,
+            influx_precision: InfluxPrecision::Ns,
+            emit_integers: false,
+            dedup: false,
+            dedup_max_gap_ms: 0,
+            smooth_alpha: None,
+            smooth_apply_to: SmoothTarget::Tb,
+            influx_ca_cert: None,
+            influx_insecure: false,
+            tb_qos: QoS::AtMostOnce,
+            tb_retain: false,
+            tb_include_ts: false,
+            tb_source: TbSource::Direct,
+            tb_query_fallback_to_direct: false,
+            extra_tags: Vec::new(),
+            heartbeat_interval_ms: None,
+            output_json: false,
+            csv_path: None,
+            measurement_raw: "sensor_raw".into(),
+            tb_publish_settle_ms: 0,
+            tb_publish_delta: None,
+            tb_max_interval_ms: 0,
+            field_units: Vec::new(),
+            influx_gzip: false,
+            tb_include_raw: false,
+            cb_failure_threshold: 3,
+            cb_cooldown: Duration::from_secs(1),
+            round_decimals: None,
+            startup_discard: Duration::from_secs(0),
+            banner_regex: None,
+            agg_window: Duration::from_secs(1),
+            allowed_fields: None,
+            field_map: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn post_line_retries_until_success() {
+        let sender = FlakyThenOkSender { fail_times: 2, attempts: Cell::new(0) };
+        let retry = RetryConfig { max_attempts: 3, base_delay: Duration::from_millis(0) };
+        let cfg = minimal_config(retry, false);
+        let result = post_line_single(&sender, &cfg, "http://example.invalid/write", "m,x=1 y=2 3");
+        assert!(result.is_ok());
+        assert_eq!(sender.attempts.get(), 3);
+    }
+
+    // Tulis `contents` ke berkas sementara unik (nama disisipi pid + label
+    // supaya test paralel tidak saling tabrakan) dan kembalikan pathnya;
+    // pemanggil tanggung jawab menghapusnya lagi lewat `std::fs::remove_file`.
+    fn write_temp_csv(label: &str, contents: &str) -> String {
+        let path = std::env::temp_dir().join(format!("run_csv_import_test_{}_{}.csv", std::process::id(), label));
+        std::fs::write(&path, contents).expect("gagal menulis CSV sementara untuk test");
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn run_csv_import_batches_rows_across_multiple_posts() {
+        let csv = "timestamp,source,temperature,humidity\n\
+                    2024-01-01T00:00:00Z,sid1,21.5,55\n\
+                    2024-01-01T00:01:00Z,sid1,21.6,55\n\
+                    2024-01-01T00:02:00Z,sid1,21.7,56\n";
+        let path = write_temp_csv("batches", csv);
+        let retry = RetryConfig { max_attempts: 1, base_delay: Duration::from_millis(0) };
+        let mut cfg = minimal_config(retry, true);
+        cfg.batch_size = 2;
+        let client = Client::new();
+
+        let imported = run_csv_import(&client, &cfg, &path);
+        std::fs::remove_file(&path).ok();
+
+        assert_eq!(imported.unwrap(), 3);
+    }
+
+    #[test]
+    fn run_csv_import_skips_rows_with_unparsable_fields() {
+        let csv = "timestamp,source,temperature,humidity\n\
+                    2024-01-01T00:00:00Z,sid1,21.5,55\n\
+                    bukan-timestamp,sid1,21.5,55\n\
+                    2024-01-01T00:02:00Z,sid1,bukan-angka,56\n";
+        let path = write_temp_csv("skips", csv);
+        let retry = RetryConfig { max_attempts: 1, base_delay: Duration::from_millis(0) };
+        let cfg = minimal_config(retry, true);
+        let client = Client::new();
+
+        let imported = run_csv_import(&client, &cfg, &path);
+        std::fs::remove_file(&path).ok();
+
+        assert_eq!(imported.unwrap(), 1);
+    }
+
+    #[test]
+    fn retry_config_delay_grows_and_caps() {
+        let rc = RetryConfig { max_attempts: 5, base_delay: Duration::from_millis(250) };
+        assert_eq!(rc.delay_for_attempt(0), Duration::from_millis(250));
+        assert_eq!(rc.delay_for_attempt(1), Duration::from_millis(500));
+        assert_eq!(rc.delay_for_attempt(2), Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn circuit_breaker_opens_after_threshold_and_blocks_requests() {
+        let mut cb = CircuitBreaker::new(3, Duration::from_secs(60));
+        assert!(cb.allow_request());
+        cb.record_failure();
+        assert!(cb.allow_request());
+        cb.record_failure();
+        assert!(cb.allow_request());
+        cb.record_failure();
+        // Kegagalan ketiga mencapai threshold -> breaker terbuka, request berikutnya ditolak.
+        assert!(!cb.allow_request());
+    }
+
+    #[test]
+    fn circuit_breaker_half_open_probe_failure_reopens_and_resets_cooldown() {
+        let mut cb = CircuitBreaker::new(1, Duration::from_millis(0));
+        cb.record_failure();
+        // Cooldown 0 -> langsung lewati ke HalfOpen dan izinkan satu probe.
+        assert!(cb.allow_request());
+        assert_eq!(cb.state, BreakerState::HalfOpen);
+        // Probe gagal harus membuka breaker lagi (bukan diam di HalfOpen selamanya).
+        cb.record_failure();
+        assert_eq!(cb.state, BreakerState::Open);
+    }
+
+    #[test]
+    fn circuit_breaker_success_closes_and_resets_failure_count() {
+        let mut cb = CircuitBreaker::new(2, Duration::from_secs(60));
+        cb.record_failure();
+        cb.record_success();
+        cb.record_failure();
+        // Sukses di antara mereset counter, jadi satu kegagalan lagi belum membuka breaker.
+        assert!(cb.allow_request());
+    }
+
+    #[test]
+    fn guard_timestamp_rejects_pre_epoch_clock() {
+        // Jam sebelum epoch (atau now_nanos() yang sudah di-clamp ke 0) tidak boleh
+        // menghasilkan timestamp negatif atau nol yang ditulis ke Influx.
+        assert_eq!(guard_timestamp(-5, TimestampSource::Host), None);
+        assert_eq!(guard_timestamp(0, TimestampSource::Host), None);
+        assert_eq!(guard_timestamp(123, TimestampSource::Host), Some(123));
+    }
+
+    #[test]
+    fn guard_timestamp_none_source_always_omits() {
+        assert_eq!(guard_timestamp(123, TimestampSource::None), None);
+    }
+
+    #[test]
+    fn influx_precision_divisor_matches_query_param() {
+        assert_eq!(InfluxPrecision::Ns.divisor(), 1);
+        assert_eq!(InfluxPrecision::Ns.query_param(), "ns");
+        assert_eq!(InfluxPrecision::Us.divisor(), 1_000);
+        assert_eq!(InfluxPrecision::Us.query_param(), "us");
+        assert_eq!(InfluxPrecision::Ms.divisor(), 1_000_000);
+        assert_eq!(InfluxPrecision::Ms.query_param(), "ms");
+        assert_eq!(InfluxPrecision::S.divisor(), 1_000_000_000);
+        assert_eq!(InfluxPrecision::S.query_param(), "s");
+
+        let raw_ns: i128 = 1_700_000_000_123_456_789;
+        assert_eq!(raw_ns / InfluxPrecision::Ms.divisor(), 1_700_000_000_123);
+        assert_eq!(raw_ns / InfluxPrecision::S.divisor(), 1_700_000_000);
+    }
+
+    #[test]
+    fn ema_first_sample_is_returned_unchanged() {
+        let mut ema = Ema::new(0.3);
+        assert_eq!(ema.update(26.0), 26.0);
+    }
+
+    #[test]
+    fn ema_smooths_towards_new_samples_without_jumping() {
+        let mut ema = Ema::new(0.5);
+        ema.update(20.0);
+        let second = ema.update(30.0);
+        assert_eq!(second, 25.0);
+        let third = ema.update(30.0);
+        assert_eq!(third, 27.5);
+    }
+
+    #[test]
+    fn latest_flux_query_filters_by_source_tag() {
+        let flux = build_latest_flux_query("sensors", "-1h", "env", "esp32-1", r#""_time","temperature","humidity""#);
+        assert!(flux.contains(r#"filter(fn: (r) => r["_measurement"] == "env")"#));
+        assert!(flux.contains(r#"filter(fn: (r) => r["source"] == "esp32-1")"#));
+    }
+
+    #[test]
+    fn read_line_capped_replaces_invalid_utf8_instead_of_erroring() {
+        let mut raw = vec![b'A', b'B', 0xFF, 0xFE, b'C'];
+        raw.push(b'\n');
+        let mut reader = std::io::Cursor::new(raw);
+        let mut line_buf = String::new();
+        let mut raw_buf = Vec::new();
+        let n = read_line_capped(&mut reader, &mut line_buf, &mut raw_buf, 4096).unwrap();
+        assert!(n > 0);
+        assert!(line_buf.contains('A') && line_buf.contains('C'));
+        assert!(line_buf.contains('\u{FFFD}'));
+    }
+
+    #[test]
+    fn parse_influx_urls_splits_trims_and_drops_empty_entries() {
+        assert_eq!(
+            parse_influx_urls("http://primary:8086, http://standby:8086 ,,"),
+            vec!["http://primary:8086".to_string(), "http://standby:8086".to_string()]
+        );
+    }
+
+    #[test]
+    fn parse_influx_urls_single_endpoint_unchanged() {
+        assert_eq!(parse_influx_urls("http://primary:8086"), vec!["http://primary:8086".to_string()]);
+    }
+
+    #[test]
+    fn startup_discard_disabled_when_zero() {
+        assert!(!is_within_startup_discard(Duration::from_millis(0), Duration::ZERO));
+        assert!(!is_within_startup_discard(Duration::from_secs(99), Duration::ZERO));
+    }
+
+    #[test]
+    fn startup_discard_active_until_window_elapses() {
+        let window = Duration::from_millis(5_000);
+        assert!(is_within_startup_discard(Duration::from_millis(100), window));
+        assert!(!is_within_startup_discard(Duration::from_millis(5_001), window));
+    }
+
+    #[test]
+    fn banner_regex_matches_configured_pattern() {
+        let re = Regex::new(r"(?i)^boot|rst:0x").unwrap();
+        assert!(re.is_match("rst:0x1 (POWERON),boot:0x13"));
+        assert!(!re.is_match("RH = 55.0 % T = 26.0 C"));
+    }
+
+    #[test]
+    fn org_query_param_falls_back_to_org_name() {
+        assert_eq!(org_query_param("my-org", None), "org=my-org");
+    }
+
+    #[test]
+    fn org_query_param_prefers_org_id_when_set() {
+        assert_eq!(org_query_param("my-org", Some("0123abc")), "orgID=0123abc");
+    }
+
+    #[test]
+    fn mask_secret_keeps_only_last_four_chars() {
+        assert_eq!(mask_secret("supersecrettoken1234"), "****************1234");
+    }
+
+    #[test]
+    fn mask_secret_masks_entirely_when_four_chars_or_fewer() {
+        assert_eq!(mask_secret("abcd"), "****");
+        assert_eq!(mask_secret("ab"), "**");
+        assert_eq!(mask_secret(""), "");
+    }
+
+    #[test]
+    fn mask_secret_opt_reports_none_without_masking() {
+        assert_eq!(mask_secret_opt(&None), "None");
+        assert_eq!(mask_secret_opt(&Some("hunter2".to_string())), "***ter2");
+    }
+
+    #[test]
+    fn is_retryable_treats_429_as_retryable() {
+        let err = anyhow!("InfluxDB write failed: 429 => rate limited");
+        assert!(is_retryable(&err));
+    }
+
+    #[test]
+    fn is_retryable_rejects_4xx_except_429() {
+        let err = anyhow!("InfluxDB write failed: 400 => bad request");
+        assert!(!is_retryable(&err));
+    }
+
+    #[test]
+    fn window_aggregator_holds_first_sample_without_flushing() {
+        let mut agg = WindowAggregator::new(Duration::from_secs(9_999));
+        assert!(agg.push(50.0, 20.0, Instant::now()).is_none());
+    }
+
+    #[test]
+    fn window_aggregator_computes_mean_count_and_population_stddev() {
+        let mut agg = WindowAggregator::new(Duration::from_secs(9_999));
+        assert!(agg.push(50.0, 20.0, Instant::now()).is_none());
+        assert!(agg.push(54.0, 24.0, Instant::now()).is_none());
+        let window = agg.flush().expect("dua sampel sudah masuk, flush tidak boleh None");
+        assert_eq!(window.count, 2);
+        assert_eq!(window.mean_rh, 52.0);
+        assert_eq!(window.mean_t, 22.0);
+        assert_eq!(window.stddev_rh, 2.0);
+        assert_eq!(window.stddev_t, 2.0);
+    }
+
+    #[test]
+    fn window_aggregator_flush_with_no_samples_returns_none() {
+        let mut agg = WindowAggregator::new(Duration::from_secs(1));
+        assert!(agg.flush().is_none());
+    }
+
+    #[test]
+    fn window_aggregator_push_closes_window_once_duration_elapses() {
+        // Window nol berarti "sudah lewat" sejak sampel pertama, jadi sampel
+        // kedua langsung menutup window pertama tanpa perlu sleep sungguhan
+        // -- pola yang sama dipakai CircuitBreaker dengan cooldown nol.
+        let mut agg = WindowAggregator::new(Duration::ZERO);
+        assert!(agg.push(50.0, 20.0, Instant::now()).is_none());
+        let closed = agg.push(60.0, 30.0, Instant::now()).expect("window harus ditutup begitu durasi terlampaui");
+        assert_eq!(closed.count, 1);
+        assert_eq!(closed.mean_rh, 50.0);
+        assert_eq!(closed.mean_t, 20.0);
+        assert_eq!(closed.stddev_rh, 0.0);
+    }
 }
 
-fn query_latest_influx(client: &Client, cfg: &Config) -> Result<Latest> {
-    let flux = format!(
+// ========================= Query Influx terbaru =========================
+/// Rakit query Flux "baris terakhir" di atas, dipisah jadi fungsi murni
+/// supaya klausa `filter` tag `source` bisa diuji tanpa HTTP sungguhan.
+/// Tanpa filter ini, deployment multi-sensor dalam satu bucket akan
+/// mengambil baris terbaru GLOBAL (sensor apa pun yang terakhir lapor),
+/// bukan baris terbaru milik sensor yang sedang dipublish ke TB.
+fn build_latest_flux_query(bucket: &str, range: &str, measurement: &str, source: &str, keep_columns: &str) -> String {
+    format!(
         r#"
 from(bucket: "{bucket}")
-  |> range(start: -7d)
+  |> range(start: {range})
   |> filter(fn: (r) => r["_measurement"] == "{measurement}")
+  |> filter(fn: (r) => r["source"] == "{source}")
   |> pivot(rowKey: ["_time"], columnKey: ["_field"], valueColumn: "_value")
-  |> keep(columns: ["_time","temperature","humidity"])
+  |> keep(columns: [{keep_columns}])
   |> sort(columns: ["_time"], desc: true)
   |> limit(n: 1)
 "#,
-        bucket = cfg.influx_bucket,
-        measurement = cfg.measurement
+        bucket = bucket,
+        range = range,
+        measurement = measurement,
+        source = source,
+        keep_columns = keep_columns
+    )
+}
+
+fn query_latest_influx(client: &Client, cfg: &Config, source: &str) -> Result<Latest> {
+    if cfg.influx_version == InfluxVersion::V1 {
+        return query_latest_influx_v1(client, cfg, source);
+    }
+    let keep_columns = format!(
+        r#""_time","{temp}","{hum}""#,
+        temp = cfg.field_temperature,
+        hum = cfg.field_humidity
     );
+    let flux = build_latest_flux_query(&cfg.influx_bucket, &cfg.query_range, &cfg.measurement, source, &keep_columns);
 
+    // Query/health-check selalu ke primary (elemen pertama) -- failover
+    // hanya berlaku di write path (`post_line`), lihat komentar pada field
+    // `influx_urls`.
     let url = format!(
-        "{}/api/v2/query?org={}",
-        cfg.influx_url.trim_end_matches('/'),
-        urlencoding::encode(&cfg.influx_org)
+        "{}/api/v2/query?{}",
+        cfg.influx_urls[0].trim_end_matches('/'),
+        org_query_param(&cfg.influx_org, cfg.influx_org_id.as_deref())
     );
 
     let resp = client
@@ -276,137 +1764,847 @@ from(bucket: "{bucket}")
     }
 
     let text = resp.text().unwrap_or_default();
-    let mut rdr = csv::ReaderBuilder::new()
-        .has_headers(true)
-        .comment(Some(b'#'))
-        .from_reader(text.as_bytes());
+    parse_latest_from_flux_csv(&text, &cfg.field_temperature, &cfg.field_humidity)
+}
 
-    let headers = rdr.headers()?.clone();
-    let i_time = headers.iter().position(|h| h == "_time")
-        .ok_or_else(|| anyhow!("Kolom _time tidak ada"))?;
-    let i_temp = headers.iter().position(|h| h == "temperature")
-        .ok_or_else(|| anyhow!("Kolom temperature tidak ada"))?;
-    let i_hum = headers.iter().position(|h| h == "humidity")
-        .ok_or_else(|| anyhow!("Kolom humidity tidak ada"))?;
+/// Mengubah durasi relatif gaya Flux (`-1h`) menjadi klausa InfluxQL
+/// (`- 1h`) yang dipasang setelah `now()` di `WHERE`.
+fn influxql_range_clause(query_range: &str) -> String {
+    format!("- {}", query_range.trim_start_matches('-'))
+}
 
-    for rec in rdr.records() {
-        let rec = rec?;
-        let t_str = rec.get(i_time).unwrap_or("");
-        let temp_str = rec.get(i_temp).unwrap_or("");
-        let hum_str = rec.get(i_hum).unwrap_or("");
-        if t_str.is_empty() || temp_str.is_empty() || hum_str.is_empty() {
-            continue;
-        }
-        let t_parsed: DateTime<Utc> = t_str.parse().context("Parse _time RFC3339 gagal")?;
-        let temp = temp_str.parse::<f64>().context("Parse temperature gagal")?;
-        let hum = hum_str.parse::<f64>().context("Parse humidity gagal")?;
-        return Ok(Latest { temperature: temp, humidity: hum, ts_ms: t_parsed.timestamp_millis() });
+/// Setara `query_latest_influx` tapi lewat InfluxQL `/query` v1 menggunakan basic auth.
+fn query_latest_influx_v1(client: &Client, cfg: &Config, source: &str) -> Result<Latest> {
+    let influxql = format!(
+        "SELECT last({temp}) AS temperature, last({hum}) AS humidity FROM \"{measurement}\" WHERE time > now() {range} AND \"source\" = '{source}'",
+        temp = cfg.field_temperature,
+        hum = cfg.field_humidity,
+        measurement = cfg.measurement,
+        range = influxql_range_clause(&cfg.query_range),
+        source = source
+    );
+    let url = format!("{}/query?db={}", cfg.influx_urls[0].trim_end_matches('/'), urlencoding::encode(&cfg.influx_bucket));
+
+    let resp = client
+        .get(&url)
+        .basic_auth(cfg.influx_user.as_deref().unwrap_or(""), cfg.influx_password.as_deref())
+        .query(&[("q", influxql.as_str())])
+        .send()
+        .context("HTTP error query InfluxDB v1")?;
+
+    if !resp.status().is_success() {
+        let code = resp.status();
+        let text = resp.text().unwrap_or_default();
+        return Err(anyhow!("Influx v1 query failed: {} => {}", code, text));
     }
 
-    Err(anyhow!("Tidak ada baris data pada hasil query Influx"))
+    let body: Value = resp.json().context("Parse respons InfluxQL gagal")?;
+    let series = body["results"][0]["series"][0]["values"][0]
+        .as_array()
+        .ok_or_else(|| anyhow!("Tidak ada baris data pada hasil query InfluxQL"))?;
+    let ts_str = series.first().and_then(|v| v.as_str()).ok_or_else(|| anyhow!("Kolom time tidak ada"))?;
+    // `last(field)` InfluxQL mengembalikan null (bukan error) kalau field itu
+    // tidak pernah ditulis di measurement ini, jadi cukup `Option`, sama
+    // seperti cabang v2 di atas.
+    let temp = series.get(1).and_then(|v| v.as_f64());
+    let hum = series.get(2).and_then(|v| v.as_f64());
+    if temp.is_none() && hum.is_none() {
+        return Err(anyhow!("Kolom temperature maupun humidity tidak ada pada hasil query InfluxQL"));
+    }
+    let t_parsed: DateTime<Utc> = ts_str.parse().context("Parse time RFC3339 gagal")?;
+
+    Ok(Latest { temperature: temp, humidity: hum, ts_ms: t_parsed.timestamp_millis() })
+}
+
+/// Bungkus `query_latest_influx` dengan retry/backoff `cfg.retry` yang sama
+/// dipakai `post_line_single` (lihat `is_retryable`), supaya satu hiccup
+/// Influx transient saat publish TB tidak langsung melewatkan satu siklus
+/// TB -- query read jauh lebih sering terjadi daripada write sehingga
+/// kebijakan retry yang sama relevan di kedua arah.
+fn query_latest_influx_with_retry(client: &Client, cfg: &Config, source: &str) -> Result<Latest> {
+    let retry = cfg.retry;
+    let mut last_err = None;
+    for attempt in 0..retry.max_attempts.max(1) {
+        match query_latest_influx(client, cfg, source) {
+            Ok(latest) => return Ok(latest),
+            Err(e) => {
+                if attempt + 1 >= retry.max_attempts || !is_retryable(&e) {
+                    return Err(e);
+                }
+                let delay = retry.delay_for_attempt(attempt);
+                warn!("Retry query_latest_influx (attempt {}/{}) setelah {:?}: {}", attempt + 1, retry.max_attempts, delay, e);
+                std::thread::sleep(delay);
+                last_err = Some(e);
+            }
+        }
+    }
+    Err(last_err.unwrap_or_else(|| anyhow!("query_latest_influx gagal tanpa error tercatat")))
 }
 
 // ========================= Publish ke ThingsBoard =========================
-fn publish_to_tb(cfg: &Config, telemetry_json: &str) -> Result<()> {
+/// Membuat client MQTT tunggal yang dipakai sepanjang hidup proses. Reconnect
+/// ditangani secara internal oleh rumqttc; kita hanya perlu mengalirkan event
+/// loop-nya di thread terpisah supaya koneksi tetap keepalive.
+fn connect_tb(cfg: &Config) -> MqttClient {
     let mut mqtt_opts = MqttOptions::new(&cfg.tb_client_id, &cfg.tb_host, cfg.tb_port);
     mqtt_opts.set_credentials(&cfg.tb_token, "");
+    // Broker (bukan kita) yang mempublish ini kalau koneksi TCP putus tanpa
+    // DISCONNECT yang bersih -- itulah bedanya dengan publish status
+    // "offline" manual, yang tidak akan pernah terkirim saat proses crash.
+    mqtt_opts.set_last_will(LastWill::new(
+        &cfg.mqtt_lwt_topic,
+        cfg.mqtt_lwt_payload.clone(),
+        QoS::AtLeastOnce,
+        false,
+    ));
 
     if cfg.tb_use_tls {
         mqtt_opts.set_transport(rumqttc::Transport::Tls(rumqttc::TlsConfiguration::default()));
     }
 
-    let (mut client, mut connection) = MqttClient::new(mqtt_opts, 10);
+    let (client, mut connection) = MqttClient::new(mqtt_opts, 10);
 
-    // jalankan reader di thread lain (biar koneksi keepalive)
     std::thread::spawn(move || {
         for _ in connection.iter() {
             // bisa log jika ingin
         }
     });
 
-    let topic = "v1/devices/me/telemetry";
-    client.publish(topic, QoS::AtLeastOnce, false, telemetry_json.as_bytes())
-        .context("MQTT publish gagal")?;
+    client
+}
+
+/// Dipakai oleh `--check`: satu request ringan ke endpoint health InfluxDB,
+/// tanpa menulis data apa pun.
+fn check_influx(cfg: &Config, http: &Client) -> Result<()> {
+    let url = match cfg.influx_version {
+        InfluxVersion::V2 => format!("{}/health", cfg.influx_urls[0].trim_end_matches('/')),
+        InfluxVersion::V1 => format!("{}/ping", cfg.influx_urls[0].trim_end_matches('/')),
+    };
+    let resp = http.get(&url).send().context("request ke Influx gagal")?;
+    if resp.status().is_success() {
+        Ok(())
+    } else {
+        Err(anyhow!("status HTTP {}", resp.status()))
+    }
+}
 
-    std::thread::sleep(Duration::from_millis(150));
+/// Dipakai oleh `--check`: coba connect MQTT ke TB dan tunggu ConnAck dengan
+/// client id terpisah supaya tidak bertabrakan dengan sesi publish yang
+/// sedang berjalan.
+fn check_tb(cfg: &Config) -> Result<()> {
+    let mut mqtt_opts = MqttOptions::new(format!("{}-healthcheck", cfg.tb_client_id), &cfg.tb_host, cfg.tb_port);
+    mqtt_opts.set_credentials(&cfg.tb_token, "");
+    if cfg.tb_use_tls {
+        mqtt_opts.set_transport(rumqttc::Transport::Tls(rumqttc::TlsConfiguration::default()));
+    }
+
+    let (_client, mut connection) = MqttClient::new(mqtt_opts, 1);
+    for notification in connection.iter() {
+        match notification {
+            Ok(rumqttc::Event::Incoming(rumqttc::Packet::ConnAck(_))) => return Ok(()),
+            Ok(_) => continue,
+            Err(e) => return Err(anyhow!("{}", e)),
+        }
+    }
+    Err(anyhow!("koneksi MQTT tertutup tanpa ConnAck"))
+}
+
+/// Jalankan kedua pengecekan dan cetak OK/FAIL per leg; `true` hanya kalau
+/// keduanya lolos.
+fn run_health_check(cfg: &Config, http: &Client) -> bool {
+    let influx_result = check_influx(cfg, http);
+    match &influx_result {
+        Ok(()) => println!("Influx: OK"),
+        Err(e) => println!("Influx: FAIL ({})", e),
+    }
+
+    let tb_result = if cfg.tb_enabled {
+        let result = check_tb(cfg);
+        match &result {
+            Ok(()) => println!("ThingsBoard: OK"),
+            Err(e) => println!("ThingsBoard: FAIL ({})", e),
+        }
+        result
+    } else {
+        println!("ThingsBoard: SKIP (TB_ENABLED=0)");
+        Ok(())
+    };
+
+    influx_result.is_ok() && tb_result.is_ok()
+}
+
+fn publish_to_tb(client: &MqttClient, telemetry_json: &str, dry_run: bool, qos: QoS, retain: bool, topic: &str, settle: Duration) -> Result<()> {
+    if dry_run {
+        info!("[dry-run] Akan publish ke {}: {}", topic, telemetry_json);
+        return Ok(());
+    }
+    client.publish(topic, qos, retain, telemetry_json.as_bytes())
+        .context("MQTT publish gagal")?;
+    // Settle time setelah publish berguna untuk broker yang butuh jeda
+    // antar pesan; TB_PUBLISH_SETTLE_MS=0 melepas jeda ini sama sekali,
+    // relevan saat koneksi MQTT persisten dan throughput tinggi dikejar.
+    if !settle.is_zero() {
+        std::thread::sleep(settle);
+    }
     Ok(())
 }
 
+// ========================= Metrics Prometheus =========================
+/// Counter/gauge yang dibagikan antar thread (loop utama + server metrics).
+/// Gauge disimpan sebagai bit pattern `f64` di `AtomicU64` karena std belum
+/// punya `AtomicF64`.
+#[derive(Default)]
+struct Metrics {
+    influx_writes_total: AtomicU64,
+    influx_write_failures_total: AtomicU64,
+    mqtt_publishes_total: AtomicU64,
+    mqtt_publish_failures_total: AtomicU64,
+    serial_lines_read_total: AtomicU64,
+    last_humidity_bits: AtomicU64,
+    last_temperature_bits: AtomicU64,
+}
+
+impl Metrics {
+    fn set_last_reading(&self, humidity: f64, temperature: f64) {
+        self.last_humidity_bits.store(humidity.to_bits(), Ordering::Relaxed);
+        self.last_temperature_bits.store(temperature.to_bits(), Ordering::Relaxed);
+    }
+
+    fn render(&self) -> String {
+        format!(
+            "# HELP influx_writes_total Total successful writes to InfluxDB.\n\
+# TYPE influx_writes_total counter\n\
+influx_writes_total {}\n\
+# HELP influx_write_failures_total Total failed writes to InfluxDB.\n\
+# TYPE influx_write_failures_total counter\n\
+influx_write_failures_total {}\n\
+# HELP mqtt_publishes_total Total successful publishes to ThingsBoard.\n\
+# TYPE mqtt_publishes_total counter\n\
+mqtt_publishes_total {}\n\
+# HELP mqtt_publish_failures_total Total failed publishes to ThingsBoard.\n\
+# TYPE mqtt_publish_failures_total counter\n\
+mqtt_publish_failures_total {}\n\
+# HELP serial_lines_read_total Total lines read from the serial/TCP source.\n\
+# TYPE serial_lines_read_total counter\n\
+serial_lines_read_total {}\n\
+# HELP last_humidity_percent Last parsed relative humidity reading.\n\
+# TYPE last_humidity_percent gauge\n\
+last_humidity_percent {}\n\
+# HELP last_temperature Last parsed temperature reading, in the configured unit.\n\
+# TYPE last_temperature gauge\n\
+last_temperature {}\n",
+            self.influx_writes_total.load(Ordering::Relaxed),
+            self.influx_write_failures_total.load(Ordering::Relaxed),
+            self.mqtt_publishes_total.load(Ordering::Relaxed),
+            self.mqtt_publish_failures_total.load(Ordering::Relaxed),
+            self.serial_lines_read_total.load(Ordering::Relaxed),
+            f64::from_bits(self.last_humidity_bits.load(Ordering::Relaxed)),
+            f64::from_bits(self.last_temperature_bits.load(Ordering::Relaxed)),
+        )
+    }
+}
+
+/// Menjalankan server HTTP kecil di thread terpisah yang menyajikan metrik
+/// Prometheus di `/metrics`. Tidak dipanggil sama sekali jika `METRICS_PORT`
+/// tidak diset, jadi tidak ada overhead untuk deployment yang tidak butuh.
+fn spawn_metrics_server(port: u16, metrics: Arc<Metrics>) {
+    std::thread::spawn(move || {
+        let server = match tiny_http::Server::http(("0.0.0.0", port)) {
+            Ok(s) => s,
+            Err(e) => {
+                error!("Gagal membuka metrics server di port {}: {}", port, e);
+                return;
+            }
+        };
+        for request in server.incoming_requests() {
+            let body = metrics.render();
+            let response = tiny_http::Response::from_string(body).with_header(
+                tiny_http::Header::from_bytes(&b"Content-Type"[..], &b"text/plain; version=0.0.4"[..])
+                    .expect("header statis selalu valid"),
+            );
+            let _ = request.respond(response);
+        }
+    });
+}
+
+// `build.rs` (lewat `vergen`/`vergen-gitcl`) mengemit env var ini saat compile,
+// dibaca di sini dengan `env!()` supaya tidak perlu parsing/IO lagi saat runtime.
+const BUILD_VERSION: &str = env!("CARGO_PKG_VERSION");
+const BUILD_GIT_SHA: &str = env!("VERGEN_GIT_SHA");
+const BUILD_TIMESTAMP: &str = env!("VERGEN_BUILD_TIMESTAMP");
+
+/// `--version` harus jalan tanpa `Config::from_env()` -- unit yang belum
+/// dikonfigurasi (atau salah konfigurasi) tetap harus bisa dicek versinya
+/// lewat tiket dukungan tanpa butuh `.env` yang valid dulu.
+fn print_version() {
+    println!("serial_to_influx {} (git {}, built {})", BUILD_VERSION, BUILD_GIT_SHA, BUILD_TIMESTAMP);
+}
+
+/// Sembunyikan semua karakter kecuali 4 terakhir -- cukup untuk operator
+/// mencocokkan "token/password mana" (mis. membandingkan dengan secret store)
+/// tanpa pernah menampilkan nilainya utuh di layar/log `--print-config`.
+fn mask_secret(s: &str) -> String {
+    let len = s.chars().count();
+    if len <= 4 {
+        "*".repeat(len)
+    } else {
+        let visible: String = s.chars().skip(len - 4).collect();
+        format!("{}{}", "*".repeat(len - 4), visible)
+    }
+}
+
+fn mask_secret_opt(s: &Option<String>) -> String {
+    s.as_deref().map(mask_secret).unwrap_or_else(|| "None".to_string())
+}
+
+/// Cetak setiap field `Config` yang sudah diresolve dari env/`.env`, dengan
+/// token/password di-mask (lihat `mask_secret`) -- dibuat untuk mendiagnosa
+/// env var yang diam-diam salah/typo (mis. `BAUDRATE` jatuh ke default tanpa
+/// ada error apa pun), yang sebelumnya hanya bisa diketahui dengan membaca
+/// ulang kode `from_env`.
+fn print_config(cfg: &Config) {
+    println!("influx_urls = {:?}", cfg.influx_urls);
+    println!("influx_token = {}", mask_secret(&cfg.influx_token));
+    println!("influx_org = {:?}", cfg.influx_org);
+    println!("influx_org_id = {:?}", cfg.influx_org_id);
+    println!("influx_bucket = {:?}", cfg.influx_bucket);
+    println!("measurement = {:?}", cfg.measurement);
+    println!("tag_source = {:?}", cfg.tag_source);
+    println!("serial_port = {:?}", cfg.serial_port);
+    println!("baudrate = {}", cfg.baudrate);
+    println!("include_raw_on_fail = {}", cfg.include_raw_on_fail);
+    println!("tb_enabled = {}", cfg.tb_enabled);
+    println!("mqtt_topic = {:?}", cfg.mqtt_topic);
+    println!("mqtt_lwt_topic = {:?}", cfg.mqtt_lwt_topic);
+    println!("mqtt_lwt_payload = {:?}", cfg.mqtt_lwt_payload);
+    println!("tb_host = {:?}", cfg.tb_host);
+    println!("tb_port = {}", cfg.tb_port);
+    println!("tb_token = {}", mask_secret(&cfg.tb_token));
+    println!("tb_client_id = {:?}", cfg.tb_client_id);
+    println!("tb_use_tls = {}", cfg.tb_use_tls);
+    println!("retry = {:?}", cfg.retry);
+    println!("batch_size = {}", cfg.batch_size);
+    println!("batch_interval = {:?}", cfg.batch_interval);
+    println!("spool_path = {:?}", cfg.spool_path);
+    println!("influx_version = {:?}", cfg.influx_version);
+    println!("influx_user = {:?}", cfg.influx_user);
+    println!("influx_password = {}", mask_secret_opt(&cfg.influx_password));
+    println!("pair_timeout = {:?}", cfg.pair_timeout);
+    println!("temp_unit = {:?}", cfg.temp_unit);
+    println!("influx_bucket_raw = {:?}", cfg.influx_bucket_raw);
+    println!("metrics_port = {:?}", cfg.metrics_port);
+    println!("query_range = {:?}", cfg.query_range);
+    println!("field_temperature = {:?}", cfg.field_temperature);
+    println!("field_humidity = {:?}", cfg.field_humidity);
+    println!("input_mode = {:?}", cfg.input_mode);
+    println!("input_tcp_addr = {:?}", cfg.input_tcp_addr);
+    println!("input_file = {:?}", cfg.input_file);
+    println!("dry_run = {}", cfg.dry_run);
+    println!("temp_min = {}", cfg.temp_min);
+    println!("temp_max = {}", cfg.temp_max);
+    println!("timestamp_source = {:?}", cfg.timestamp_source);
+    println!("influx_precision = {:?}", cfg.influx_precision);
+    println!("emit_integers = {}", cfg.emit_integers);
+    println!("dedup = {}", cfg.dedup);
+    println!("dedup_max_gap_ms = {}", cfg.dedup_max_gap_ms);
+    println!("smooth_alpha = {:?}", cfg.smooth_alpha);
+    println!("smooth_apply_to = {:?}", cfg.smooth_apply_to);
+    println!("influx_ca_cert = {:?}", cfg.influx_ca_cert);
+    println!("influx_insecure = {}", cfg.influx_insecure);
+    println!("tb_qos = {:?}", cfg.tb_qos);
+    println!("tb_retain = {}", cfg.tb_retain);
+    println!("tb_include_ts = {}", cfg.tb_include_ts);
+    println!("tb_source = {:?}", cfg.tb_source);
+    println!("tb_query_fallback_to_direct = {}", cfg.tb_query_fallback_to_direct);
+    println!("extra_tags = {:?}", cfg.extra_tags);
+    println!("heartbeat_interval_ms = {:?}", cfg.heartbeat_interval_ms);
+    println!("output_json = {}", cfg.output_json);
+    println!("csv_path = {:?}", cfg.csv_path);
+    println!("measurement_raw = {:?}", cfg.measurement_raw);
+    println!("tb_publish_settle_ms = {}", cfg.tb_publish_settle_ms);
+    println!("tb_publish_delta = {:?}", cfg.tb_publish_delta);
+    println!("tb_max_interval_ms = {}", cfg.tb_max_interval_ms);
+    println!("field_units = {:?}", cfg.field_units);
+    println!("influx_gzip = {}", cfg.influx_gzip);
+    println!("tb_include_raw = {}", cfg.tb_include_raw);
+    println!("cb_failure_threshold = {}", cfg.cb_failure_threshold);
+    println!("cb_cooldown = {:?}", cfg.cb_cooldown);
+    println!("round_decimals = {:?}", cfg.round_decimals);
+    println!("startup_discard = {:?}", cfg.startup_discard);
+    println!("banner_regex = {:?}", cfg.banner_regex.as_ref().map(|r| r.as_str()));
+    println!("agg_window = {:?}", cfg.agg_window);
+    println!("allowed_fields = {:?}", cfg.allowed_fields);
+    println!("field_map = {:?}", cfg.field_map);
+}
+
 // ========================= MAIN LOOP =========================
 fn main() -> Result<()> {
+    if env::args().any(|a| a == "--version") {
+        print_version();
+        return Ok(());
+    }
+
     dotenv().ok();
+    env_logger::init();
     let cfg = Config::from_env()?;
 
-    println!(
-        "Membaca serial {} @{} dan menulis ke InfluxDB bucket={} org={} measurement={}",
-        cfg.serial_port, cfg.baudrate, cfg.influx_bucket, cfg.influx_org, cfg.measurement
-    );
-
-    let sp = open_serial(&cfg.serial_port, cfg.baudrate)?;
-    let mut reader = BufReader::new(sp);
+    if env::args().any(|a| a == "--print-config") {
+        print_config(&cfg);
+        return Ok(());
+    }
 
-    let http = Client::builder()
-        .timeout(Duration::from_secs(8))
+    let mut http_builder = Client::builder().timeout(Duration::from_secs(8));
+    if let Some(ca_path) = &cfg.influx_ca_cert {
+        let pem = std::fs::read(ca_path).with_context(|| format!("Gagal membaca INFLUX_CA_CERT {}", ca_path))?;
+        let cert = reqwest::Certificate::from_pem(&pem).context("INFLUX_CA_CERT bukan PEM yang valid")?;
+        http_builder = http_builder.add_root_certificate(cert);
+    }
+    if cfg.influx_insecure {
+        warn!("INFLUX_INSECURE aktif: verifikasi sertifikat TLS InfluxDB DINONAKTIFKAN. Jangan pakai di produksi!");
+        http_builder = http_builder.danger_accept_invalid_certs(true);
+    }
+    let http = http_builder
         .build()
         .context("Gagal membuat HTTP client")?;
-    let write_url = build_write_url(&cfg);
+
+    // `--check`/`MODE=check` hanya memverifikasi konektivitas Influx+TB, tanpa
+    // membuka serial/TCP input, supaya skrip provisioning bisa cek kredensial
+    // sebelum unit benar-benar dipasang di lapangan.
+    if env::args().any(|a| a == "--check") || env::var("MODE").map(|v| v == "check").unwrap_or(false) {
+        return if run_health_check(&cfg, &http) {
+            info!("Health check OK");
+            Ok(())
+        } else {
+            error!("Health check FAIL");
+            std::process::exit(1);
+        };
+    }
+
+    // `--import <file>` membackfill CSV lama (mis. hasil CsvSink) ke Influx
+    // dengan timestamp aslinya, lalu keluar tanpa membuka serial/TCP input.
+    if let Some(path) = env::args().skip_while(|a| a != "--import").nth(1) {
+        let imported = run_csv_import(&http, &cfg, &path)
+            .with_context(|| format!("Import CSV {} gagal", path))?;
+        info!("Import CSV selesai: {} baris dari {}", imported, path);
+        return Ok(());
+    }
+
+    match cfg.input_mode {
+        InputMode::Serial => info!(
+            "Membaca serial {} @{} dan menulis ke InfluxDB bucket={} org={} measurement={}",
+            cfg.serial_port, cfg.baudrate, cfg.influx_bucket, cfg.influx_org, cfg.measurement
+        ),
+        InputMode::Tcp => info!(
+            "Membaca TCP {} dan menulis ke InfluxDB bucket={} org={} measurement={}",
+            cfg.input_tcp_addr.as_deref().unwrap_or(""), cfg.influx_bucket, cfg.influx_org, cfg.measurement
+        ),
+        InputMode::File => info!(
+            "Membaca replay dari {} dan menulis ke InfluxDB bucket={} org={} measurement={}",
+            cfg.input_file.as_deref().unwrap_or("-"), cfg.influx_bucket, cfg.influx_org, cfg.measurement
+        ),
+    }
+
+    let input = open_input(&cfg)?;
+    let mut reader = BufReader::new(input);
+
+    let write_urls = build_write_urls(&cfg, &cfg.influx_bucket);
+    // Bucket sekunder untuk data mentah/raw (tiering); jika tidak dikonfigurasi
+    // perilakunya identik dengan sebelumnya karena memakai URL yang sama.
+    let write_urls_raw = match &cfg.influx_bucket_raw {
+        Some(bucket) => build_write_urls(&cfg, bucket),
+        None => write_urls.clone(),
+    };
+
+    if let Some(path) = &cfg.spool_path {
+        match spool_drain(&http, &cfg, &write_urls, path) {
+            Ok(n) if n > 0 => info!("Spool {} berhasil dikirim ulang: {} baris", path, n),
+            Ok(_) => {}
+            Err(e) => error!("Gagal drain spool {} saat start: {}", path, e),
+        }
+    }
+
+    // DRY_RUN=1 tidak boleh membuka koneksi sungguhan ke mana pun (lihat
+    // `post_line`/`publish_to_tb`) -- `connect_tb` sendiri sudah memulai
+    // thread event-loop MQTT di background begitu dipanggil, jauh sebelum
+    // `publish_to_tb` (yang dry-run-aware) sempat dicek.
+    let tb_client = if cfg.tb_enabled && !cfg.dry_run { Some(connect_tb(&cfg)) } else { None };
+
+    let metrics = Arc::new(Metrics::default());
+    if let Some(port) = cfg.metrics_port {
+        spawn_metrics_server(port, metrics.clone());
+        info!("Metrics Prometheus aktif di 0.0.0.0:{}/metrics", port);
+    }
 
     let mut buf = String::new();
-    let mut pending = Pending { rh: None, t: None };
+    let mut raw_buf: Vec<u8> = Vec::new();
+    let mut pending = Pending::new();
+    let mut batch = LineBuffer::new(cfg.cb_failure_threshold, cfg.cb_cooldown);
+    let mut raw_batch = LineBuffer::new(cfg.cb_failure_threshold, cfg.cb_cooldown);
+    let mut last_spool_drain = Instant::now();
+    // Dipakai oleh heartbeat `bridge_status`: beda dengan last_written (yang
+    // hanya update saat RH/T benar-benar ditulis, bisa skip karena dedup),
+    // ini update pada setiap baris yang berhasil di-parse apa pun jalurnya,
+    // supaya last_read_age_ms benar-benar mengukur "kapan terakhir kita
+    // dengar sesuatu dari sumbernya".
+    let mut last_successful_read = Instant::now();
+    let mut last_heartbeat = Instant::now();
+    let mut last_published_to_tb: Option<(f64, f64, Instant)> = None;
+    let mut csv_sink = cfg.csv_path.clone().map(CsvSink::new);
+    // Beberapa detik pertama setelah power-on, ESP sering nyemburkan garbage
+    // bootloader/banner sebelum pembacaan bersih -- STARTUP_DISCARD_MS
+    // menahan baris-baris itu dari ditulis ke Influx tanpa mematikan parsing/
+    // logging-nya (jadi operator masih bisa lihat apa yang masuk lewat log).
+    let startup_at = Instant::now();
+    // Dipakai oleh mode DEDUP=1 untuk membandingkan pembacaan RH/T baru
+    // dengan titik terakhir yang benar-benar ditulis.
+    let mut last_written: Option<(f64, f64, Instant)> = None;
+    // Alpha 1.0 (tanpa SMOOTH_ALPHA) membuat Ema::update selalu mengembalikan
+    // sampel mentah, jadi EMA tetap aman dipakai tanpa cabang jika smoothing nonaktif.
+    let mut rh_ema = Ema::new(cfg.smooth_alpha.unwrap_or(1.0));
+    let mut temp_ema = Ema::new(cfg.smooth_alpha.unwrap_or(1.0));
+    // AGG_WINDOW_MS=0 (default) berarti agregasi nonaktif -- RH/T tetap
+    // ditulis satu titik per sampel seperti sebelumnya, aggregator ini
+    // hanya dipakai kalau operator benar-benar mengaktifkannya.
+    let mut agg = WindowAggregator::new(cfg.agg_window);
+    // Versi firmware dari banner boot `FW:1.2.3` terakhir -- dipakai sebagai
+    // tag `fw=` pada titik berikutnya sampai banner baru datang (mis. setelah
+    // OTA/reboot), supaya rollout bisa dikonfirmasi lewat distribusi tag di Influx.
+    let mut fw_version: Option<String> = None;
+
+    // Tandai flag ini pada SIGINT/SIGTERM agar loop utama bisa flush buffer dan
+    // menutup koneksi MQTT dengan bersih sebelum keluar (penting untuk systemd restart).
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let shutdown_flag = shutdown.clone();
+    ctrlc::set_handler(move || shutdown_flag.store(true, Ordering::SeqCst))
+        .context("Gagal memasang signal handler")?;
 
     loop {
+        if shutdown.load(Ordering::SeqCst) {
+            info!("Menerima sinyal shutdown, flush buffer dan keluar...");
+            batch.flush(&http, &cfg, &write_urls, &metrics);
+            raw_batch.flush(&http, &cfg, &write_urls_raw, &metrics);
+            return Ok(());
+        }
+
         buf.clear();
-        let n = reader.read_line(&mut buf)?;
+        let n = match read_line_capped(&mut reader, &mut buf, &mut raw_buf, MAX_LINE_BYTES) {
+            Ok(0) if cfg.input_mode == InputMode::File => {
+                batch.flush(&http, &cfg, &write_urls, &metrics);
+                raw_batch.flush(&http, &cfg, &write_urls_raw, &metrics);
+                info!("EOF pada INPUT_FILE, replay selesai");
+                return Ok(());
+            }
+            Ok(0) if cfg.input_mode == InputMode::Tcp => {
+                warn!("Koneksi TCP input putus, menyambung ulang...");
+                reader = reconnect_tcp_input(&cfg)?;
+                continue;
+            }
+            Ok(n) => n,
+            Err(e) if cfg.input_mode == InputMode::Tcp => {
+                warn!("Gagal membaca TCP input: {}, menyambung ulang...", e);
+                reader = reconnect_tcp_input(&cfg)?;
+                continue;
+            }
+            Err(e) if cfg.input_mode == InputMode::File => return Err(e.into()),
+            Err(e) => {
+                warn!("Gagal membaca serial {}: {}, menyambung ulang...", cfg.serial_port, e);
+                reader = reconnect_serial_input(&cfg)?;
+                continue;
+            }
+        };
         if n == 0 { continue; }
+        metrics.serial_lines_read_total.fetch_add(1, Ordering::Relaxed);
         let trimmed = buf.trim();
         if trimmed.is_empty() { continue; }
 
-        // 1) Tangkap format "RH = x%" / "T = y °C" -> kirim ke Influx sebagai satu point
-        if let Some((rh, t)) = update_pending_from_line(&mut pending, trimmed) {
-            let ts = now_nanos();
-            let meas = escape_measurement(&cfg.measurement);
-            let tag = escape_tag_key_or_value(&cfg.tag_source);
-            let lp = format!("{},source={} temperature={},humidity={} {}",
-                             meas, tag, t, rh, ts);
+        // 0a) BANNER_REGEX menandai garbage bootloader/banner lain yang tidak
+        // cocok pola FW:x.y.z tapi tetap bukan data -- dibuang total, tidak
+        // pernah masuk parser lain maupun jadi raw fallback.
+        if let Some(re) = &cfg.banner_regex {
+            if re.is_match(trimmed) {
+                debug!("BANNER_REGEX cocok, baris dibuang: {}", trimmed);
+                continue;
+            }
+        }
 
-            if let Err(e) = post_line(&http, &cfg, &write_url, &lp) {
-                eprintln!("Gagal kirim RH/T ke Influx: {} | {}", e, lp);
+        // 0b) Beberapa detik pertama setelah boot (STARTUP_DISCARD_MS) tetap
+        // diparse & dilog seperti biasa di bawah, tapi ditandai di sini agar
+        // titik push ke batch/raw_batch di-skip -- operator masih bisa lihat
+        // apa yang masuk lewat log tanpa junk boot ikut tersimpan di Influx.
+        let discard_due_to_startup = is_within_startup_discard(startup_at.elapsed(), cfg.startup_discard);
+
+        // 0c) Banner boot firmware "FW:1.2.3" -- simpan versinya, jangan diteruskan
+        // ke parser lain (bukan baris data, jadi tidak pernah cocok RH/T/relay).
+        if let Some(v) = parse_fw_version(trimmed) {
+            info!("Firmware melaporkan versi {}", v);
+            fw_version = Some(v);
+            continue;
+        }
+
+        // 1) Tangkap format "RH = x%" / "T = y °C" -> masuk ke buffer batch sebagai satu point
+        if let Some((rh, t)) = update_pending_from_line(&mut pending, trimmed, cfg.pair_timeout) {
+            last_successful_read = Instant::now();
+            if !is_plausible_reading(rh, t, cfg.temp_min, cfg.temp_max) {
+                warn!("Pembacaan RH/T tidak plausible (RH={}, T={}), dibuang", rh, t);
+            } else if cfg.dedup && is_duplicate_reading(last_written, rh, t, Instant::now(), cfg.dedup_max_gap_ms) {
+                debug!("DEDUP: RH/T tidak berubah (RH={}, T={}), lewati tulis", rh, t);
             } else {
-                println!("OK Influx (RH/T): RH={}%, T={}°C", rh, t);
-
-                // 2) Setelah berhasil masuk ke Influx, ambil data terbaru dari Influx
-                match query_latest_influx(&http, &cfg) {
-                    Ok(latest) => {
-                        // 3) Kirim ke ThingsBoard (payload tanpa ts; TB pakai server time)
-                        let json_payload = serde_json::json!({
-                            "temperature": latest.temperature,
-                            "humidity": latest.humidity
-                            // Jika ingin sertakan timestamp:
-                            // "ts": latest.ts_ms
-                        }).to_string();
-
-                        if let Err(e) = publish_to_tb(&cfg, &json_payload) {
-                            eprintln!("Gagal publish ke TB: {}", e);
+                last_written = Some((rh, t, Instant::now()));
+                let ts = timestamp_for_line(cfg.timestamp_source, cfg.influx_precision);
+                let meas = escape_measurement(&cfg.measurement);
+                let tag = escape_tag_key_or_value(&resolve_source_tag(trimmed, &cfg.tag_source));
+                let (t_out, unit_str) = match cfg.temp_unit {
+                    TempUnit::Celsius => (t, "C"),
+                    TempUnit::Fahrenheit => (celsius_to_fahrenheit(t), "F"),
+                };
+                // Dew point selalu dihitung dari Celsius mentah (rumus Magnus tidak
+                // berlaku untuk Fahrenheit), lalu dikonversi ke satuan yang sama
+                // dengan field temperature supaya konsisten di satu baris.
+                let dewpoint_c = dew_point(t, rh);
+                let dewpoint_out = match cfg.temp_unit {
+                    TempUnit::Celsius => dewpoint_c,
+                    TempUnit::Fahrenheit => celsius_to_fahrenheit(dewpoint_c),
+                };
+
+                // EMA dihitung sekali per pembacaan (stateful, tidak boleh dipanggil
+                // dua kali untuk sampel yang sama) lalu dipilih tujuannya lewat config.
+                let smoothed_t = temp_ema.update(t_out);
+                let smoothed_rh = rh_ema.update(rh);
+                let (influx_t_out, influx_rh) = match cfg.smooth_apply_to {
+                    SmoothTarget::Influx | SmoothTarget::Both => (smoothed_t, smoothed_rh),
+                    SmoothTarget::Tb => (t_out, rh),
+                };
+
+                let extra = extra_tags_suffix(&cfg.extra_tags);
+                let unit_tags = field_unit_tags_suffix(unit_str, &cfg.field_units);
+                let fw_tag = fw_tag_suffix(fw_version.as_deref());
+                let lp = append_timestamp(
+                    format!("{},source={}{}{}{},quality=rht temperature={},humidity={},dewpoint={}",
+                             meas, tag, extra, unit_tags, fw_tag,
+                             round_decimals(influx_t_out, cfg.round_decimals),
+                             round_decimals(influx_rh, cfg.round_decimals),
+                             round_decimals(dewpoint_out, cfg.round_decimals)),
+                    ts,
+                );
+                if discard_due_to_startup {
+                    debug!("STARTUP_DISCARD_MS aktif, baris RH/T dibuang dari tulisan Influx");
+                } else if cfg.agg_window.is_zero() {
+                    batch.push(lp);
+                } else if let Some(window) = agg.push(influx_rh, influx_t_out, Instant::now()) {
+                    // AGG_WINDOW_MS aktif: satu titik per window (bukan per sampel),
+                    // membawa `count`/`*_stddev` supaya statistik yang hilang karena
+                    // decimation masih bisa dilihat di Influx.
+                    let agg_lp = append_timestamp(
+                        format!(
+                            "{},source={}{}{}{},quality=rht temperature={},humidity={},count={}i,temperature_stddev={},humidity_stddev={}",
+                            meas, tag, extra, unit_tags, fw_tag,
+                            round_decimals(window.mean_t, cfg.round_decimals),
+                            round_decimals(window.mean_rh, cfg.round_decimals),
+                            window.count,
+                            round_decimals(window.stddev_t, cfg.round_decimals),
+                            round_decimals(window.stddev_rh, cfg.round_decimals),
+                        ),
+                        ts,
+                    );
+                    batch.push(agg_lp);
+                }
+                debug!("Antrian Influx (RH/T): RH={}%, T={}{}", rh, t_out, unit_str);
+                metrics.set_last_reading(rh, t_out);
+
+                // OUTPUT_JSON=1: ops tooling lain ingin JSON lines di stdout,
+                // terpisah dari log manusia (env_logger sudah menulis ke
+                // stderr secara default, jadi tidak ada yang campur).
+                if cfg.output_json {
+                    let json_line = serde_json::json!({
+                        "ts": ts.unwrap_or_else(now_nanos),
+                        "temperature": t_out,
+                        "humidity": rh,
+                        "source": resolve_source_tag(trimmed, &cfg.tag_source),
+                    });
+                    println!("{}", json_line);
+                }
+
+                // CSV_PATH jalan berdampingan dengan Influx/TB, bukan
+                // menggantikannya -- sink lokal untuk QA yang mau lihat
+                // riwayat tanpa query Influx.
+                if let Some(sink) = &mut csv_sink {
+                    let row_ts = nanos_to_datetime(ts.map(|t| t * cfg.influx_precision.divisor()).unwrap_or_else(now_nanos));
+                    let row_source = resolve_source_tag(trimmed, &cfg.tag_source);
+                    if let Err(e) = sink.write_row(row_ts, &row_source, t_out, rh) {
+                        error!("Gagal menulis CSV_PATH: {}", e);
+                    }
+                }
+
+                // 2) Siapkan nilai yang akan dipublish ke ThingsBoard. `TB_SOURCE=direct`
+                // memakai nilai yang baru diparse langsung (tanpa round-trip query Influx,
+                // yang menambah latensi dan satu titik gagal); default `influx` tetap
+                // query balik seperti sebelumnya (perlu untuk agregasi multi-writer).
+                // Kalau smoothing hanya dituju ke TB, Influx/nilai baru tetap mentah jadi
+                // kita timpa di sini dengan hasil EMA alih-alih nilai mentah tersebut.
+                // DRY_RUN: `tb_client` tidak pernah dibuat, jadi query balik ke
+                // Influx untuk TB juga dilewati.
+                let tb_values = if !cfg.tb_enabled || cfg.dry_run {
+                    None
+                } else {
+                    match cfg.tb_source {
+                    TbSource::Direct => {
+                        let (temp, hum) = if cfg.smooth_alpha.is_some() && cfg.smooth_apply_to == SmoothTarget::Tb {
+                            (smoothed_t, smoothed_rh)
                         } else {
-                            println!("Published to ThingsBoard ✅  {}", json_payload);
+                            (influx_t_out, influx_rh)
+                        };
+                        let ts_ms = ts.map(|t| (t * cfg.influx_precision.divisor() / 1_000_000) as i64)
+                            .unwrap_or_else(|| (now_nanos() / 1_000_000) as i64);
+                        Some((Some(temp), Some(hum), ts_ms))
+                    }
+                    TbSource::Influx => match query_latest_influx_with_retry(&http, &cfg, &resolve_source_tag(trimmed, &cfg.tag_source)) {
+                        Ok(latest) => {
+                            let (temp, hum) = if cfg.smooth_alpha.is_some() && cfg.smooth_apply_to == SmoothTarget::Tb {
+                                (Some(smoothed_t), Some(smoothed_rh))
+                            } else {
+                                (latest.temperature, latest.humidity)
+                            };
+                            Some((temp, hum, latest.ts_ms))
+                        }
+                        Err(e) => {
+                            error!("Query Influx terbaru gagal setelah retry: {}", e);
+                            if cfg.tb_query_fallback_to_direct {
+                                // TB_QUERY_FALLBACK_TO_DIRECT=1: daripada diam satu siklus,
+                                // publish nilai yang baru saja diparse (persis TbSource::Direct)
+                                // supaya TB tetap terupdate walau query read-nya gagal total.
+                                let (temp, hum) = if cfg.smooth_alpha.is_some() && cfg.smooth_apply_to == SmoothTarget::Tb {
+                                    (smoothed_t, smoothed_rh)
+                                } else {
+                                    (influx_t_out, influx_rh)
+                                };
+                                let ts_ms = ts.map(|t| (t * cfg.influx_precision.divisor() / 1_000_000) as i64)
+                                    .unwrap_or_else(|| (now_nanos() / 1_000_000) as i64);
+                                Some((Some(temp), Some(hum), ts_ms))
+                            } else {
+                                None
+                            }
                         }
+                    },
+                    }
+                };
+
+                let tb_values = tb_values.filter(|(temp, hum, _)| {
+                    let (temp, hum) = (temp.unwrap_or(0.0), hum.unwrap_or(0.0));
+                    should_publish_to_tb(last_published_to_tb, temp, hum, Instant::now(), cfg.tb_publish_delta, cfg.tb_max_interval_ms)
+                });
+
+                if let (Some(client), Some((tb_temperature, tb_humidity, tb_ts_ms))) = (&tb_client, tb_values) {
+                    // Hanya field yang benar-benar ada yang masuk payload --
+                    // query Influx yang cuma punya salah satu (lihat `Latest`)
+                    // tidak boleh mengirim 0/null yang menyesatkan dashboard.
+                    let mut values_map = serde_json::Map::new();
+                    if let Some(t) = tb_temperature {
+                        values_map.insert("temperature".to_string(), serde_json::json!(round_decimals(t, cfg.round_decimals)));
+                    }
+                    if let Some(h) = tb_humidity {
+                        values_map.insert("humidity".to_string(), serde_json::json!(round_decimals(h, cfg.round_decimals)));
+                    }
+                    // TB_INCLUDE_RAW=1 menambah pasangan raw/smoothed di samping
+                    // key default (yang tidak berubah) supaya dashboard yang mau
+                    // bandingkan efek EMA bisa plot kedua trace tanpa query Influx.
+                    if cfg.tb_include_raw {
+                        values_map.insert("temperature_raw".to_string(), serde_json::json!(round_decimals(t_out, cfg.round_decimals)));
+                        values_map.insert("temperature_smoothed".to_string(), serde_json::json!(round_decimals(smoothed_t, cfg.round_decimals)));
+                        values_map.insert("humidity_raw".to_string(), serde_json::json!(round_decimals(rh, cfg.round_decimals)));
+                        values_map.insert("humidity_smoothed".to_string(), serde_json::json!(round_decimals(smoothed_rh, cfg.round_decimals)));
+                    }
+                    values_map.insert("dewpoint".to_string(), serde_json::json!(round_decimals(dewpoint_out, cfg.round_decimals)));
+                    let values = Value::Object(values_map);
+                    // TB_INCLUDE_TS=1 membungkus payload dalam format timestamp
+                    // eksplisit ThingsBoard, dipakai saat bridge bisa lag dari
+                    // real-time (spool drain, batch) sehingga server time TB salah.
+                    let json_payload = if cfg.tb_include_ts {
+                        serde_json::json!({ "ts": tb_ts_ms, "values": values }).to_string()
+                    } else {
+                        values.to_string()
+                    };
+
+                    if let Err(e) = publish_to_tb(client, &json_payload, cfg.dry_run, cfg.tb_qos, cfg.tb_retain, &cfg.mqtt_topic, Duration::from_millis(cfg.tb_publish_settle_ms)) {
+                        error!("Gagal publish ke TB: {}", e);
+                        metrics.mqtt_publish_failures_total.fetch_add(1, Ordering::Relaxed);
+                    } else {
+                        info!("Published to ThingsBoard ✅  {}", json_payload);
+                        metrics.mqtt_publishes_total.fetch_add(1, Ordering::Relaxed);
+                        last_published_to_tb = Some((
+                            tb_temperature.unwrap_or(0.0),
+                            tb_humidity.unwrap_or(0.0),
+                            Instant::now(),
+                        ));
                     }
-                    Err(e) => eprintln!("Query Influx terbaru gagal: {}", e),
                 }
             }
 
             // reset pending per pasangan
-            pending.rh = None;
-            pending.t = None;
-            continue;
+            pending.clear();
+        } else if let Some((relay_id, state)) = parse_relay_state(trimmed) {
+            // 3) Aktuasi relay (`Relay1 ON ...`) masuk ke bucket utama (bukan raw)
+            // sebagai field `relay_state` supaya bisa dikorelasikan langsung dengan
+            // temperature/humidity di Grafana lewat source tag yang sama.
+            let ts = timestamp_for_line(cfg.timestamp_source, cfg.influx_precision);
+            let meas = escape_measurement(&cfg.measurement);
+            let tag = escape_tag_key_or_value(&resolve_source_tag(trimmed, &cfg.tag_source));
+            let relay_tag = relay_id
+                .map(|id| format!(",relay={}", escape_tag_key_or_value(&id)))
+                .unwrap_or_default();
+            let fw_tag = fw_tag_suffix(fw_version.as_deref());
+            let lp = append_timestamp(
+                format!("{},source={}{}{} relay_state={}i", meas, tag, relay_tag, fw_tag, state),
+                ts,
+            );
+            if discard_due_to_startup {
+                debug!("STARTUP_DISCARD_MS aktif, baris relay dibuang dari tulisan Influx");
+            } else {
+                batch.push(lp);
+            }
+            last_successful_read = Instant::now();
+            debug!("Antrian Influx (relay): state={}", state);
+        } else if let Some(lp) = line_to_influx(&cfg.measurement_raw, &cfg.tag_source, trimmed, cfg.include_raw_on_fail, timestamp_for_line(cfg.timestamp_source, cfg.influx_precision), cfg.emit_integers, &cfg.extra_tags, cfg.allowed_fields.as_deref(), if cfg.field_map.is_empty() { None } else { Some(cfg.field_map.as_slice()) }) {
+            // 4) Jika bukan format RH/T, fallback ke parser generik lama -> bucket raw/tiering
+            if discard_due_to_startup {
+                debug!("STARTUP_DISCARD_MS aktif, baris raw fallback dibuang dari tulisan Influx");
+            } else {
+                raw_batch.push(lp);
+            }
+            last_successful_read = Instant::now();
         }
 
-        // 4) Jika bukan format RH/T, fallback ke parser generik lama
-        if let Some(lp) = line_to_influx(&cfg.measurement, &cfg.tag_source, trimmed, cfg.include_raw_on_fail) {
-            if let Err(e) = post_line(&http, &cfg, &write_url, &lp) {
-                eprintln!("Gagal kirim (generic): {} | {}", e, lp);
-            } else {
-                println!("OK Influx (generic): {}", trimmed);
+        if batch.should_flush(&cfg) {
+            batch.flush(&http, &cfg, &write_urls, &metrics);
+        }
+        if raw_batch.should_flush(&cfg) {
+            raw_batch.flush(&http, &cfg, &write_urls_raw, &metrics);
+        }
+
+        // Heartbeat ditulis terlepas dari ada/tidaknya pembacaan sukses, supaya
+        // Grafana bisa membedakan "sensor mati" (bridge tetap menulis up=1i,
+        // last_read_age_ms naik terus) dari "bridge mati" (tidak ada titik
+        // baru sama sekali).
+        if let Some(interval_ms) = cfg.heartbeat_interval_ms {
+            if last_heartbeat.elapsed() >= Duration::from_millis(interval_ms) {
+                let tag = escape_tag_key_or_value(&cfg.tag_source);
+                let lp = format!(
+                    "bridge_status,source={} up=1i,last_read_age_ms={}i",
+                    tag,
+                    last_successful_read.elapsed().as_millis()
+                );
+                batch.push(lp);
+                last_heartbeat = Instant::now();
+            }
+        }
+
+        if let Some(path) = &cfg.spool_path {
+            if last_spool_drain.elapsed() >= Duration::from_secs(30) {
+                match spool_drain(&http, &cfg, &write_urls, path) {
+                    Ok(n) if n > 0 => info!("Spool {} berhasil dikirim ulang: {} baris", path, n),
+                    Ok(_) => {}
+                    Err(e) => error!("Gagal drain spool {}: {}", path, e),
+                }
+                last_spool_drain = Instant::now();
             }
         }
     }